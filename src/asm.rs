@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::MEM_SIZE;
+use crate::disasm::{lookup, Format, CX, JX};
+use crate::rom::Rom;
+use crate::symbols::Symbols;
+
+/// Condition suffixes accepted on top of the disassembler's. `jmz` and `jmc`
+/// are the pre-1.0 jumps, which tchip16 assembles as `jz` and `jc`.
+const CONDITION_ALIASES: [(&str, usize); 4] = [("mz", 0x0), ("mc", 0x9), ("nc", 0x8), ("c", 0x9)];
+
+/// A program assembled from tchip16 style source.
+pub struct Assembly {
+    pub data: Vec<u8>,
+    /// Label and `equ` values by name.
+    pub symbols: HashMap<String, u16>,
+    /// Labels by address, without the `equ` constants.
+    pub labels: Symbols,
+}
+
+impl Assembly {
+    /// Wraps the program in a CH16 header that starts execution at 0x0000,
+    /// as tchip16 does.
+    pub fn to_rom(&self, spec: (u8, u8)) -> Rom {
+        return Rom::with_header(spec, 0x0000, self.data.clone());
+    }
+}
+
+struct Location {
+    file: PathBuf,
+    line: usize,
+}
+
+enum Kind {
+    Instruction(String),
+    Db,
+    Dw,
+    Import(Vec<u8>),
+}
+
+struct Statement {
+    location: Location,
+    kind: Kind,
+    operands: Vec<String>,
+}
+
+#[derive(Default)]
+struct Parser {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, u16>,
+    constants: Vec<(String, String, Location)>,
+    imports: Vec<(String, Vec<u8>, Location)>,
+    addr: usize,
+    depth: usize,
+}
+
+fn error(location: &Location, message: String) -> String {
+    return format!("{}:{}: {}", location.file.display(), location.line, message);
+}
+
+/// Splits a line into tokens on whitespace and commas, keeping quoted strings
+/// whole, and drops anything after a `;`.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in line.chars() {
+        if quoted {
+            token.push(c);
+            if c == '"' {
+                quoted = false;
+            }
+            continue;
+        }
+        match c {
+            ';' => break,
+            '"' => {
+                token.push(c);
+                quoted = true;
+            }
+            ',' | ' ' | '\t' | '\r' => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            _ => token.push(c),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    return tokens;
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let (negative, token) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = token.strip_prefix('#').or_else(|| token.strip_prefix('$')) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(hex) = token
+        .strip_prefix("0x")
+        .or_else(|| token.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(hex) = token.strip_suffix('h').or_else(|| token.strip_suffix('H')) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = token.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else {
+        token.parse::<i64>().ok()?
+    };
+    return Some(if negative { -value } else { value });
+}
+
+fn parse_register(token: &str) -> Option<u8> {
+    let lower = token.to_ascii_lowercase();
+    let digit = lower.strip_prefix('r')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    return u8::from_str_radix(digit, 16).ok();
+}
+
+impl Parser {
+    fn parse_file(&mut self, path: &Path) -> Result<(), String> {
+        let source = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        // Some of the bundled sources aren't valid UTF-8, so decode as Latin-1
+        let source: String = source.iter().map(|byte| *byte as char).collect();
+        return self.parse_source(&source, path);
+    }
+
+    fn parse_source(&mut self, source: &str, path: &Path) -> Result<(), String> {
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        for (index, line) in source.lines().enumerate() {
+            let location = Location {
+                file: path.to_path_buf(),
+                line: index + 1,
+            };
+            self.parse_line(line, location, &dir)?;
+        }
+        return Ok(());
+    }
+
+    fn parse_line(&mut self, line: &str, location: Location, dir: &Path) -> Result<(), String> {
+        let mut tokens = tokenize(line);
+
+        if tokens.len() >= 3 && tokens[1].eq_ignore_ascii_case("equ") {
+            let value = tokens[2].clone();
+            self.constants
+                .push((tokens.swap_remove(0), value, location));
+            return Ok(());
+        }
+
+        if let Some(first) = tokens.first() {
+            let label = first
+                .strip_prefix(':')
+                .or_else(|| first.strip_suffix(':'))
+                .map(str::to_string);
+            if let Some(label) = label {
+                self.define(label, &location)?;
+                tokens.remove(0);
+            }
+        }
+
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        let mnemonic = tokens.remove(0).to_ascii_lowercase();
+
+        let (kind, size) = match mnemonic.as_str() {
+            "include" => {
+                let file = tokens
+                    .first()
+                    .ok_or_else(|| error(&location, String::from("include needs a file")))?;
+                self.depth += 1;
+                if self.depth > 16 {
+                    return Err(error(&location, String::from("includes nested too deeply")));
+                }
+                self.parse_file(&dir.join(file))?;
+                self.depth -= 1;
+                return Ok(());
+            }
+            "importbin" => {
+                if tokens.len() != 4 {
+                    return Err(error(
+                        &location,
+                        String::from("importbin needs a file, offset, length and label"),
+                    ));
+                }
+                let path = dir.join(&tokens[0]);
+                let bytes = std::fs::read(&path)
+                    .map_err(|e| error(&location, format!("{}: {}", path.display(), e)))?;
+                let offset = parse_number(&tokens[1])
+                    .filter(|offset| *offset >= 0)
+                    .ok_or_else(|| error(&location, format!("bad offset {}", tokens[1])))?;
+                let len = parse_number(&tokens[2])
+                    .filter(|len| (0..=0xFFFF).contains(len))
+                    .ok_or_else(|| error(&location, format!("bad length {}", tokens[2])))?;
+                // Like tchip16, anything past the end of the file reads as zero
+                let mut bytes: Vec<u8> = bytes.into_iter().skip(offset as usize).collect();
+                bytes.resize(len as usize, 0);
+                self.imports.push((tokens[3].clone(), bytes, location));
+                return Ok(());
+            }
+            "db" => {
+                let size = tokens
+                    .iter()
+                    .map(|token| match token.strip_prefix('"') {
+                        Some(text) => text.trim_end_matches('"').len(),
+                        None => 1,
+                    })
+                    .sum();
+                (Kind::Db, size)
+            }
+            "dw" => (Kind::Dw, tokens.len() * 2),
+            _ => (Kind::Instruction(mnemonic), 4),
+        };
+
+        self.statements.push(Statement {
+            location,
+            kind,
+            operands: tokens,
+        });
+        self.addr += size;
+        return Ok(());
+    }
+
+    fn define(&mut self, label: String, location: &Location) -> Result<(), String> {
+        if self.symbols.contains_key(&label) {
+            return Err(error(location, format!("{} is defined twice", label)));
+        }
+        self.symbols.insert(label, self.addr as u16);
+        return Ok(());
+    }
+}
+
+/// What an operand turned out to be, used to pick between opcodes that share
+/// a mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    Reg(u8),
+    Sp,
+    Value(u16),
+}
+
+fn fits(format: Format, args: &[Arg]) -> bool {
+    use Arg::*;
+    return match format {
+        Format::None => args.is_empty(),
+        Format::N | Format::Hhll | Format::Code | Format::Palette => matches!(args, [Value(_)]),
+        Format::Rx => matches!(args, [Reg(_)]),
+        Format::RxRy => matches!(args, [Reg(_), Reg(_)]),
+        Format::RxRyRz => matches!(args, [Reg(_), Reg(_), Reg(_)]),
+        Format::RxHhll | Format::RxN => matches!(args, [Reg(_), Value(_)]),
+        Format::RxRyCode | Format::RxRySprite => matches!(args, [Reg(_), Reg(_), Value(_)]),
+        Format::SpHhll => matches!(args, [Sp, Value(_)]),
+        Format::Flip | Format::Sng => matches!(args, [Value(_), Value(_)]),
+    };
+}
+
+/// Resolves a symbol or number, symbols first so one can't be mistaken for a
+/// number.
+fn number(token: &str, symbols: &HashMap<String, u16>) -> Result<i64, String> {
+    if let Some(value) = symbols.get(token) {
+        return Ok(i64::from(*value));
+    }
+    return parse_number(token).ok_or_else(|| format!("unknown symbol {}", token));
+}
+
+fn value(token: &str, symbols: &HashMap<String, u16>) -> Result<u16, String> {
+    let value = number(token, symbols)?;
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(format!("{} doesn't fit in 16 bits", token));
+    }
+    return Ok(value as u16);
+}
+
+fn byte(token: &str, symbols: &HashMap<String, u16>) -> Result<u8, String> {
+    let value = number(token, symbols)?;
+    if !(-0x80..=0xFF).contains(&value) {
+        return Err(format!("{} doesn't fit in 8 bits", token));
+    }
+    return Ok(value as u8);
+}
+
+fn arg(token: &str, symbols: &HashMap<String, u16>) -> Result<Arg, String> {
+    if let Some(reg) = parse_register(token) {
+        return Ok(Arg::Reg(reg));
+    }
+    if token.eq_ignore_ascii_case("sp") {
+        return Ok(Arg::Sp);
+    }
+    return Ok(Arg::Value(value(token, symbols)?));
+}
+
+fn condition(mnemonic: &str, prefix: char, names: &[&str; 15]) -> Option<usize> {
+    if let Some(index) = names.iter().position(|name| *name == mnemonic) {
+        return Some(index);
+    }
+    let rest = mnemonic.strip_prefix(prefix)?;
+    return CONDITION_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == rest)
+        .map(|(_, index)| *index);
+}
+
+fn encode(mnemonic: &str, args: &[Arg]) -> Result<[u8; 4], String> {
+    let mut instruction = [0u8; 4];
+    let mut name = mnemonic;
+
+    // Conditional jumps and calls carry the condition in the second byte
+    if let Some(cond) = condition(mnemonic, 'j', &JX) {
+        instruction[1] = cond as u8;
+        name = "j";
+    } else if let Some(cond) = condition(mnemonic, 'c', &CX) {
+        instruction[1] = cond as u8;
+        name = "c";
+    }
+
+    let candidates: Vec<(u8, Format)> = (0..=0xFFu8)
+        .filter_map(|opcode| match lookup(opcode) {
+            Some((m, format)) if m == name => Some((opcode, format)),
+            _ => None,
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Err(format!("unknown instruction {}", mnemonic));
+    }
+    let (opcode, format) = *candidates
+        .iter()
+        .find(|(_, format)| fits(*format, args))
+        .ok_or_else(|| format!("wrong operands for {}", mnemonic))?;
+    instruction[0] = opcode;
+
+    let reg = |index: usize| match args[index] {
+        Arg::Reg(reg) => reg,
+        _ => 0,
+    };
+    let value = |index: usize| match args[index] {
+        Arg::Value(value) => value,
+        _ => 0,
+    };
+    let set_hhll = |instruction: &mut [u8; 4], value: u16| {
+        instruction[2..4].copy_from_slice(&value.to_le_bytes());
+    };
+
+    match format {
+        Format::None => {}
+        Format::N => instruction[2] = value(0) as u8 & 0xF,
+        Format::Hhll | Format::Code | Format::Palette => set_hhll(&mut instruction, value(0)),
+        Format::Rx => instruction[1] = reg(0),
+        Format::RxRy => instruction[1] = reg(1) << 4 | reg(0),
+        Format::RxRyRz => {
+            instruction[1] = reg(1) << 4 | reg(0);
+            instruction[2] = reg(2);
+        }
+        Format::RxHhll => {
+            instruction[1] = reg(0);
+            set_hhll(&mut instruction, value(1));
+        }
+        Format::RxN => {
+            instruction[1] = reg(0);
+            instruction[2] = value(1) as u8 & 0xF;
+        }
+        Format::RxRyCode | Format::RxRySprite => {
+            instruction[1] = reg(1) << 4 | reg(0);
+            set_hhll(&mut instruction, value(2));
+        }
+        Format::SpHhll => set_hhll(&mut instruction, value(1)),
+        Format::Flip => instruction[3] = (value(0) as u8 & 1) << 1 | (value(1) as u8 & 1),
+        Format::Sng => {
+            instruction[1] = value(0) as u8;
+            set_hhll(&mut instruction, value(1));
+        }
+    }
+    return Ok(instruction);
+}
+
+/// Assembles a tchip16 style source file. `include` and `importbin` paths
+/// are relative to the file that uses them.
+pub fn assemble_file(path: &Path) -> Result<Assembly, String> {
+    let mut parser = Parser::default();
+    parser.parse_file(path)?;
+    return finish(parser);
+}
+
+/// Assembles source text as if it were read from `path`.
+pub fn assemble(source: &str, path: &Path) -> Result<Assembly, String> {
+    let mut parser = Parser::default();
+    parser.parse_source(source, path)?;
+    return finish(parser);
+}
+
+fn finish(mut parser: Parser) -> Result<Assembly, String> {
+    // tchip16 places imported binaries after the rest of the program
+    for (label, bytes, location) in std::mem::take(&mut parser.imports) {
+        parser.define(label, &location)?;
+        parser.addr += bytes.len();
+        parser.statements.push(Statement {
+            location,
+            kind: Kind::Import(bytes),
+            operands: vec![],
+        });
+    }
+    if parser.addr > MEM_SIZE {
+        return Err(format!(
+            "program is {} bytes, larger than memory",
+            parser.addr
+        ));
+    }
+
+    // Name each address after its alphabetically first label so the output
+    // doesn't depend on hash order
+    let mut names: Vec<(&String, &u16)> = parser.symbols.iter().collect();
+    names.sort();
+    let mut labels = Symbols::default();
+    for (name, addr) in names {
+        labels.insert(*addr, name);
+    }
+
+    for (name, value, location) in &parser.constants {
+        let value = parse_number(value)
+            .ok_or_else(|| error(location, format!("{} is not a number", value)))?;
+        if parser.symbols.insert(name.clone(), value as u16).is_some() {
+            return Err(error(location, format!("{} is defined twice", name)));
+        }
+    }
+
+    let symbols = &parser.symbols;
+    let mut data = Vec::with_capacity(parser.addr);
+    for statement in &parser.statements {
+        let location = &statement.location;
+        match &statement.kind {
+            Kind::Import(bytes) => data.extend_from_slice(bytes),
+            Kind::Db => {
+                for operand in &statement.operands {
+                    match operand.strip_prefix('"') {
+                        Some(text) => {
+                            data.extend(text.trim_end_matches('"').chars().map(|c| c as u8))
+                        }
+                        None => {
+                            let byte = byte(operand, symbols).map_err(|e| error(location, e))?;
+                            data.push(byte);
+                        }
+                    }
+                }
+            }
+            Kind::Dw => {
+                for operand in &statement.operands {
+                    let value = value(operand, symbols).map_err(|e| error(location, e))?;
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            Kind::Instruction(mnemonic) => {
+                let args = statement
+                    .operands
+                    .iter()
+                    .map(|operand| arg(operand, symbols))
+                    .collect::<Result<Vec<Arg>, String>>()
+                    .map_err(|e| error(location, e))?;
+                let instruction = encode(mnemonic, &args).map_err(|e| error(location, e))?;
+                data.extend_from_slice(&instruction);
+            }
+        }
+    }
+
+    return Ok(Assembly {
+        data,
+        symbols: parser.symbols,
+        labels,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    #[test]
+    fn assembles_tchip16_syntax() {
+        let source = "
+SCREEN_W equ 320        ; constants can be used before or after definition
+start:  ldi r0, SCREEN_W
+        ldi sp, #FDF0
+:loop   addi, rA, 1
+        jmz loop
+        jmc end
+        flip 1, 0
+        sng 0xAD, 62456
+end:    db \"Hi\", 0x0A
+        dw $1234
+";
+        let assembly = assemble(source, Path::new("test.asm")).unwrap();
+        let text: Vec<String> = (0..6)
+            .map(|index| disassemble(&assembly.data, index * 4).to_string())
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "ldi r0, 0x0140",
+                "ldi sp, 0xFDF0",
+                "addi ra, 0x0001",
+                "jz 0x0008",
+                "jb 0x001C",
+                "flip 1, 0",
+            ]
+        );
+        assert_eq!(&assembly.data[24..28], &[0x0E, 0xAD, 0xF8, 0xF3]);
+        assert_eq!(&assembly.data[28..], b"Hi\n\x34\x12");
+        assert_eq!(assembly.symbols["end"], 0x001C);
+        assert_eq!(assembly.labels.get(0x0008), Some("loop"));
+        assert_eq!(assembly.labels.get(0x0140), None);
+    }
+
+    #[test]
+    fn reports_errors_with_location() {
+        let error = assemble("nop\nldi r0, missing", Path::new("test.asm"))
+            .err()
+            .unwrap();
+        assert_eq!(error, "test.asm:2: unknown symbol missing");
+        let error = assemble("add r0, 1, 2", Path::new("test.asm"))
+            .err()
+            .unwrap();
+        assert_eq!(error, "test.asm:1: wrong operands for add");
+        let error = assemble("db 1, 0x100", Path::new("test.asm"))
+            .err()
+            .unwrap();
+        assert_eq!(error, "test.asm:1: 0x100 doesn't fit in 8 bits");
+    }
+
+    #[test]
+    fn tells_symbols_from_h_suffixed_numbers() {
+        // each would otherwise read as 0xEAC
+        let source = "each equ 5\nldi r0, each\nldi r1, fffh\ndb -1, 255";
+        let assembly = assemble(source, Path::new("test.asm")).unwrap();
+        assert_eq!(
+            assembly.data,
+            [0x20, 0x00, 0x05, 0x00, 0x20, 0x01, 0xFF, 0x0F, 0xFF, 0xFF]
+        );
+    }
+}
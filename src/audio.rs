@@ -1,18 +1,10 @@
-extern crate sdl2;
-
+use chip16::sound::{wave_form_from_num, Sound, SoundState, WaveForm};
+use chip16::{AUDIO_SAMPLE_RATE, FPS};
 use rand::Rng;
 use sdl2::audio::AudioCallback;
 use sdl2::audio::AudioDevice;
-use sdl2::audio::AudioSpec;
-use std::f32::MAX;
+use std::sync::mpsc::Sender;
 use std::time::Duration;
-use std::time::Instant;
-use std::{fs::File, io::Write};
-
-use sdl2::audio::AudioQueue;
-
-use crate::AUDIO_SAMPLE_RATE;
-use crate::FPS;
 
 const SAMPLES_PER_MS: f64 = AUDIO_SAMPLE_RATE as f64 / 1000.0;
 
@@ -27,20 +19,17 @@ const RELEASE_DURATIONS: [u32; 16] = [
     6, 24, 48, 72, 114, 168, 204, 240, 300, 750, 1500, 2400, 3000, 9000, 15000, 24000,
 ];
 
-pub enum WaveForm {
-    Triangle = 0,
-    Sawtooth,
-    Square,
-    Noise,
-}
-
-pub struct Wave<'a> {
+pub struct Wave {
     period_samples: f64,
     phase_inc: f64,
     phase: f64,
     volume: f64,
     gen_function: fn(&mut Wave) -> f64,
-    out_file: &'a mut File,
+    // Where the samples played are sent to be recorded, if anywhere
+    recorder: Option<Sender<Vec<f32>>>,
+    muted: bool,
+    // False between sounds, which only plays silence when recording
+    playing: bool,
 
     // Triangle wave support
     prev: f64,
@@ -54,12 +43,12 @@ pub struct Wave<'a> {
     decay_samples: u32,
     sustain_samples: u32,
     release_samples: u32,
-    sample_progress: u32,
-    sample_inc: u32,
+    sample_progress: f64,
+    sample_inc: f64,
     sustain: f64,
 }
 
-pub fn default_wave(output_file: &mut File) -> Wave {
+pub fn default_wave(recorder: Option<Sender<Vec<f32>>>) -> Wave {
     Wave {
         period_samples: 0.0,
         phase_inc: 0.0,
@@ -67,7 +56,9 @@ pub fn default_wave(output_file: &mut File) -> Wave {
         volume: 10_000.0,
         sustain: 10_000.0,
         gen_function: gen_triangle_wave,
-        out_file: output_file,
+        recorder,
+        muted: false,
+        playing: false,
         prev: 0.0,
         y: 0.0,
         x: 0.0,
@@ -77,8 +68,8 @@ pub fn default_wave(output_file: &mut File) -> Wave {
         decay_samples: 0,
         sustain_samples: 0,
         release_samples: 0,
-        sample_progress: 0,
-        sample_inc: 0,
+        sample_progress: 0.0,
+        sample_inc: 0.0,
     }
 }
 
@@ -125,10 +116,6 @@ fn gen_triangle_wave(wave: &mut Wave) -> f64 {
     wave.x = value;
 
     value = wave.y * 0.8;
-    //println!(
-    //    "value: {}, x: {}, y: {}, prev: {} ",
-    //    value, wave.x, wave.y, wave.prev
-    //);
     return value;
 }
 
@@ -145,7 +132,7 @@ fn precompute_cycles(wave: &mut Wave) {
     }
 }
 
-impl Wave<'_> {
+impl Wave {
     fn increment_phase(&mut self) {
         self.phase += self.phase_inc;
         if self.phase > 1.0 {
@@ -158,93 +145,89 @@ impl Wave<'_> {
             return MAX_VOLUME;
         }
 
+        let attack_samples = self.attack_samples as f64;
+        let decay_samples = self.decay_samples as f64;
+        let sustain_samples = self.sustain_samples as f64;
+        let release_samples = self.release_samples as f64;
+
         // attack
-        if self.sample_progress <= self.attack_samples {
-            //dbg_println!("In attack");
-            return self.volume * (self.sample_progress as f64 / self.attack_samples as f64);
+        if self.sample_progress <= attack_samples {
+            return self.volume * (self.sample_progress / attack_samples);
         }
 
         // decay
-        let decay_threshold = self.attack_samples;
-        if self.sample_progress <= (decay_threshold + self.decay_samples) {
-            //dbg_println!("In decay");
+        let decay_threshold = attack_samples;
+        if self.sample_progress <= (decay_threshold + decay_samples) {
             return self.sustain
                 + (self.volume - self.sustain)
-                    * (1.0
-                        - (self.sample_progress - decay_threshold) as f64
-                            / self.decay_samples as f64);
+                    * (1.0 - (self.sample_progress - decay_threshold) / decay_samples);
         }
 
         // sustain
-        let sustain_threshold = self.attack_samples + self.decay_samples;
-        if self.sample_progress <= (sustain_threshold + self.sustain_samples) {
-            //dbg_println!("In sustain");
+        let sustain_threshold = attack_samples + decay_samples;
+        if self.sample_progress <= (sustain_threshold + sustain_samples) {
             return self.sustain;
         }
 
         // release
-        let release_threshold = self.attack_samples + self.decay_samples + self.sustain_samples;
+        let release_threshold = attack_samples + decay_samples + sustain_samples;
 
-        if self.sample_progress <= (release_threshold + self.release_samples) {
-            //dbg_println!("In release");
+        if self.sample_progress <= (release_threshold + release_samples) {
             return self.sustain
-                * (1.0
-                    - (self.sample_progress - release_threshold) as f64
-                        / self.release_samples as f64);
+                * (1.0 - (self.sample_progress - release_threshold) / release_samples);
         }
 
         return 0.0;
     }
 }
 
-impl AudioCallback for Wave<'_> {
+impl AudioCallback for Wave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
         for x in out.iter_mut() {
+            if self.muted || !self.playing {
+                *x = 0.0;
+                continue;
+            }
             self.sample_progress += self.sample_inc;
             let volume = self.calculate_volume();
-            //println!("vol: {}", volume);
             *x = ((self.gen_function)(self) * volume) as f32;
-            instr_dbg_println!(
-                "Generated {} from phase {}, sample_progress {}, volume {}",
-                *x,
-                self.phase,
-                self.sample_progress,
-                volume
-            );
             self.increment_phase();
+        }
 
-            let integer_result = (*x * 10_000.0) as i16;
-            //println!(
-            //    "Volume: {}, phase: {}, result: {}, writing: {}",
-            //    self.volume, self.phase, *x, integer_result
-            //);
-            self.out_file
-                .write_all(&integer_result.to_le_bytes())
-                .expect("Failed to write audio to file");
+        // Nothing is recorded while muted, which is while paused or running
+        // uncapped. The writer thread does the file I/O; if it has stopped,
+        // so does recording.
+        if let Some(recorder) = &self.recorder {
+            if !self.muted && recorder.send(out.to_vec()).is_err() {
+                self.recorder = None;
+            }
         }
     }
 }
 
-pub fn wave_form_from_num(index: u8) -> Result<WaveForm, String> {
-    return match index {
-        0 => Ok(WaveForm::Triangle),
-        1 => Ok(WaveForm::Sawtooth),
-        2 => Ok(WaveForm::Square),
-        3 => Ok(WaveForm::Noise),
-        _ => Err(String::from("Invalid wave type index")),
-    };
+// Plays the tone at `speed` times its pitch and envelope rate, so it keeps
+// pace with emulation running faster or slower than real time.
+fn resample(wave: &mut Wave, frequency: i32, speed: Option<f64>) {
+    wave.muted = speed.is_none();
+    let speed = speed.unwrap_or(1.0);
+    wave.phase_inc = frequency as f64 * speed / AUDIO_SAMPLE_RATE as f64;
+    wave.period_samples = AUDIO_SAMPLE_RATE as f64 / (frequency as f64 * speed);
+    wave.sample_inc = speed;
 }
 
-pub struct AudioState<'a> {
+pub struct AudioState {
     frequency: i32,
     duration: Duration,
     duration_ms: u16,
     total_duration_ms: u32,
     playing: bool,
-    started_at: Instant,
-    device: &'a mut AudioDevice<Wave<'a>>,
+    // Frames run since the sound started
+    frames: u32,
+    // Emulation speed, None when muted
+    speed: Option<f64>,
+    device: AudioDevice<Wave>,
     use_custom_params: bool,
     attack: usize,
     decay: usize,
@@ -252,18 +235,21 @@ pub struct AudioState<'a> {
     sustain: f64,
     volume: f64,
     wave_form: WaveForm,
+    // The raw SNG parameters, kept for save states
+    params: [u8; 6],
 }
 
-impl AudioState<'_> {
-    pub fn new<'a>(device: &'a mut AudioDevice<Wave<'a>>) -> AudioState<'a> {
+impl AudioState {
+    pub fn new(device: AudioDevice<Wave>) -> AudioState {
         return AudioState {
             frequency: 0,
             duration: Duration::new(0, 0),
             duration_ms: 0,
             total_duration_ms: 0,
             playing: false,
-            started_at: Instant::now(),
-            device: device,
+            frames: 0,
+            speed: Some(1.0),
+            device,
             use_custom_params: false,
             attack: 0,
             decay: 0,
@@ -271,81 +257,19 @@ impl AudioState<'_> {
             sustain: MAX_VOLUME,
             volume: MAX_VOLUME,
             wave_form: WaveForm::Square,
+            params: [0, 0, 0, 0, 0, WaveForm::Square as u8],
         };
     }
 
-    pub fn play_sound(&mut self, frequency: u16, duration: u16) {
-        self.use_custom_params = false;
-        self.wave_form = WaveForm::Square;
-        self.volume = MAX_VOLUME;
-        self.frequency = frequency as i32;
-        self.duration = Duration::new(0, (duration as u32) * 1_000_000);
-        self.duration_ms = duration;
-        self.total_duration_ms = duration as u32;
-        self.update_wave();
-        self.start();
-    }
-
-    pub fn play_custom_sound(&mut self, frequency: u16, duration: u16) {
-        let sustain_duration =
-            if ATTACK_DURATIONS[self.attack] + DECAY_DURATIONS[self.decay] < duration as u32 {
-                duration as u32 - ATTACK_DURATIONS[self.attack] - DECAY_DURATIONS[self.decay]
-            } else {
-                0
-            };
-
-        let total_duration = ATTACK_DURATIONS[self.attack]
-            + DECAY_DURATIONS[self.decay]
-            + (sustain_duration)
-            + RELEASE_DURATIONS[self.release];
-        self.total_duration_ms = total_duration;
-        self.duration_ms = duration;
-        self.frequency = frequency as i32;
-        self.duration = Duration::new(0, (total_duration) * 1_000_000);
-        self.use_custom_params = true;
-        instr_dbg_println!(
-            "Playing {}hz for {}ms, dur: {}, atk: {}ms, dec: {}ms, sus: {}ms, rel: {}ms",
-            self.frequency,
-            total_duration,
-            duration,
-            ATTACK_DURATIONS[self.attack],
-            DECAY_DURATIONS[self.decay],
-            sustain_duration,
-            RELEASE_DURATIONS[self.release]
-        );
-
-        self.update_wave();
-        self.start();
-    }
-
-    pub fn set_params(
-        &mut self,
-        attack: u8,
-        decay: u8,
-        sustain: u8,
-        release: u8,
-        volume: u8,
-        wave_type: u8,
-    ) -> Result<(), String> {
-        self.attack = attack as usize;
-        self.decay = decay as usize;
-        self.release = release as usize;
-        self.sustain = MAX_VOLUME / (2.0 * (16.0 - sustain as f64));
-        self.volume = MAX_VOLUME / (2.0 * (16.0 - volume as f64));
-        self.wave_form = wave_form_from_num(wave_type)?;
-        Ok(())
-    }
-
     pub fn update_wave(&mut self) {
-        self.started_at = Instant::now();
+        self.frames = 0;
 
         let mut wave = self.device.lock();
 
-        wave.phase_inc = self.frequency as f64 / AUDIO_SAMPLE_RATE as f64;
-        wave.period_samples = AUDIO_SAMPLE_RATE as f64 / self.frequency as f64;
+        resample(&mut wave, self.frequency, self.speed);
         wave.use_custom_params = self.use_custom_params;
-        wave.sample_progress = 0;
-        wave.sample_inc = 1;
+        wave.sample_progress = 0.0;
+        wave.playing = true;
 
         if self.use_custom_params {
             wave.volume = self.volume;
@@ -366,19 +290,10 @@ impl AudioState<'_> {
             };
 
             wave.sustain_samples = (SAMPLES_PER_MS * sustain_duration as f64) as u32;
-
-            instr_dbg_println!(
-                "atk: {}, dec: {}, sus: {}, rel: {}",
-                wave.attack_samples,
-                wave.decay_samples,
-                wave.sustain_samples,
-                wave.release_samples
-            );
         }
 
         match self.wave_form {
             WaveForm::Triangle => {
-                instr_dbg_println!("Selected triangle wave");
                 wave.gen_function = gen_triangle_wave;
                 // If we have a prev value then we don't need to precompute
                 if !(wave.prev != 0.0 || wave.x != 0.0 || wave.y != 0.0) {
@@ -386,39 +301,85 @@ impl AudioState<'_> {
                 }
             }
             WaveForm::Square => {
-                instr_dbg_println!("Selected square wave");
                 wave.gen_function = gen_square_wave;
             }
             WaveForm::Sawtooth => {
-                instr_dbg_println!("Selected sawtooth wave");
                 wave.gen_function = gen_sawtooth_wave;
             }
             WaveForm::Noise => {
-                instr_dbg_println!("Selected noise wave");
                 wave.gen_function = gen_noise;
             }
         }
-        instr_dbg_println!(
-            "Playing {}hz for {}ms, ~{} samples",
-            self.frequency,
-            self.total_duration_ms,
-            SAMPLES_PER_MS * (self.total_duration_ms as f64),
-        );
 
         self.playing = true;
     }
+}
 
-    pub fn is_finished(&mut self) -> bool {
-        let passed_duration = self.started_at.elapsed();
+impl Sound for AudioState {
+    fn play_sound(&mut self, frequency: u16, duration: u16) {
+        self.use_custom_params = false;
+        self.wave_form = WaveForm::Square;
+        self.volume = MAX_VOLUME;
+        self.frequency = frequency as i32;
+        self.duration = Duration::new(0, (duration as u32) * 1_000_000);
+        self.duration_ms = duration;
+        self.total_duration_ms = duration as u32;
+        self.update_wave();
+        self.start();
+    }
+
+    fn play_custom_sound(&mut self, frequency: u16, duration: u16) {
+        let sustain_duration =
+            if ATTACK_DURATIONS[self.attack] + DECAY_DURATIONS[self.decay] < duration as u32 {
+                duration as u32 - ATTACK_DURATIONS[self.attack] - DECAY_DURATIONS[self.decay]
+            } else {
+                0
+            };
+
+        let total_duration = ATTACK_DURATIONS[self.attack]
+            + DECAY_DURATIONS[self.decay]
+            + (sustain_duration)
+            + RELEASE_DURATIONS[self.release];
+        self.total_duration_ms = total_duration;
+        self.duration_ms = duration;
+        self.frequency = frequency as i32;
+        self.duration = Duration::new(0, (total_duration) * 1_000_000);
+        self.use_custom_params = true;
+
+        self.update_wave();
+        self.start();
+    }
+
+    fn set_params(
+        &mut self,
+        attack: u8,
+        decay: u8,
+        sustain: u8,
+        release: u8,
+        volume: u8,
+        wave_type: u8,
+    ) -> Result<(), String> {
+        self.attack = attack as usize;
+        self.decay = decay as usize;
+        self.release = release as usize;
+        self.sustain = MAX_VOLUME / (2.0 * (16.0 - sustain as f64));
+        self.volume = MAX_VOLUME / (2.0 * (16.0 - volume as f64));
+        self.wave_form = wave_form_from_num(wave_type)?;
+        self.params = [attack, decay, sustain, release, volume, wave_type];
+        Ok(())
+    }
+
+    fn is_finished(&mut self) -> bool {
+        let passed_duration = Duration::from_secs_f64(self.frames as f64 / FPS as f64);
         return self.playing && passed_duration > self.duration;
     }
 
-    pub fn clear(&mut self) {
+    fn clear(&mut self) {
         self.frequency = 0;
         self.duration = Duration::new(0, 0);
         self.duration_ms = 0;
         self.playing = false;
-        self.started_at = Instant::now();
+        self.frames = 0;
         self.use_custom_params = false;
         self.attack = 0;
         self.decay = 0;
@@ -426,11 +387,16 @@ impl AudioState<'_> {
         self.sustain = MAX_VOLUME;
         self.volume = MAX_VOLUME;
         self.wave_form = WaveForm::Square;
-        //self.device.pause();
-        self.device.pause();
-        instr_dbg_println!("finished and clearing");
+        self.params = [0, 0, 0, 0, 0, WaveForm::Square as u8];
 
         let mut wave = self.device.lock();
+        // Keep playing silence while recording so the gaps between sounds
+        // are recorded too
+        if wave.recorder.is_none() {
+            drop(wave);
+            self.device.pause();
+            wave = self.device.lock();
+        }
 
         wave.period_samples = 0.0;
         wave.phase_inc = 0.0;
@@ -445,13 +411,65 @@ impl AudioState<'_> {
         wave.decay_samples = 0;
         wave.sustain_samples = 0;
         wave.release_samples = 0;
-        wave.sample_progress = 0;
-        wave.sample_inc = 0;
-
-        wave.out_file.sync_all().expect("Failed to sync audio file");
+        wave.sample_progress = 0.0;
+        wave.sample_inc = 0.0;
+        wave.playing = false;
     }
 
-    pub fn start(&mut self) {
+    fn start(&mut self) {
         self.device.resume();
     }
+
+    fn frame(&mut self) {
+        if self.playing {
+            self.frames += 1;
+        }
+    }
+
+    fn set_speed(&mut self, speed: Option<f64>) {
+        if speed == self.speed {
+            return;
+        }
+        self.speed = speed;
+        let mut wave = self.device.lock();
+        if self.playing {
+            resample(&mut wave, self.frequency, speed);
+        } else {
+            wave.muted = speed.is_none();
+        }
+    }
+
+    fn state(&mut self) -> SoundState {
+        return SoundState {
+            frequency: self.frequency as u16,
+            duration: self.duration_ms,
+            params: self.params,
+            custom: self.use_custom_params,
+            playing: self.playing,
+            position: self.device.lock().sample_progress as u32,
+        };
+    }
+
+    fn restore(&mut self, state: &SoundState) {
+        self.clear();
+        let [attack, decay, sustain, release, volume, wave_type] = state.params;
+        if self
+            .set_params(attack, decay, sustain, release, volume, wave_type)
+            .is_err()
+        {
+            return;
+        }
+        if !state.playing {
+            return;
+        }
+
+        if state.custom {
+            self.play_custom_sound(state.frequency, state.duration);
+        } else {
+            self.play_sound(state.frequency, state.duration);
+        }
+        // Pick the envelope up where it was saved
+        self.device.lock().sample_progress = state.position as f64;
+        self.frames = (state.position as u64 * FPS as u64 / AUDIO_SAMPLE_RATE as u64) as u32;
+    }
 }
@@ -0,0 +1,245 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::cpu::Controller;
+
+/// Pad buttons by config name, with their bit in the controller register.
+pub const BUTTONS: [(&str, Controller); 8] = [
+    ("up", 0b00000001),
+    ("down", 0b00000010),
+    ("left", 0b00000100),
+    ("right", 0b00001000),
+    ("select", 0b00010000),
+    ("start", 0b00100000),
+    ("a", 0b01000000),
+    ("b", 0b10000000),
+];
+
+/// A host input, named the way SDL names it: `key:W`, `button:dpup` or
+/// `axis:-lefty` for pushing the left stick up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Key(String),
+    Button(String),
+    Axis { axis: String, positive: bool },
+}
+
+impl Input {
+    pub fn parse(input: &str) -> Result<Input, String> {
+        let (kind, name) = input
+            .split_once(':')
+            .ok_or_else(|| format!("{} should look like key:W, button:a or axis:-leftx", input))?;
+        return match kind {
+            "key" => Ok(Input::Key(name.to_string())),
+            "button" => Ok(Input::Button(name.to_string())),
+            "axis" => match (name.strip_prefix('+'), name.strip_prefix('-')) {
+                (Some(axis), _) => Ok(Input::Axis {
+                    axis: axis.to_string(),
+                    positive: true,
+                }),
+                (_, Some(axis)) => Ok(Input::Axis {
+                    axis: axis.to_string(),
+                    positive: false,
+                }),
+                _ => Err(format!("{} needs a + or - direction", input)),
+            },
+            _ => Err(format!("{} isn't a key, button or axis", input)),
+        };
+    }
+}
+
+/// One host input driving one pad button.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub input: Input,
+    pub pad: usize,
+    pub button: Controller,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    /// Gamepad inputs are read from the first gamepad plugged in for pad 1
+    /// and the second for pad 2.
+    pub bindings: Vec<Binding>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    pad1: Option<PadConfig>,
+    pad2: Option<PadConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PadConfig {
+    #[serde(default)]
+    up: Vec<String>,
+    #[serde(default)]
+    down: Vec<String>,
+    #[serde(default)]
+    left: Vec<String>,
+    #[serde(default)]
+    right: Vec<String>,
+    #[serde(default)]
+    select: Vec<String>,
+    #[serde(default)]
+    start: Vec<String>,
+    #[serde(default)]
+    a: Vec<String>,
+    #[serde(default)]
+    b: Vec<String>,
+}
+
+impl PadConfig {
+    fn inputs(&self) -> [&[String]; 8] {
+        return [
+            &self.up,
+            &self.down,
+            &self.left,
+            &self.right,
+            &self.select,
+            &self.start,
+            &self.a,
+            &self.b,
+        ];
+    }
+}
+
+const DEFAULT_PAD1: [&[&str]; 8] = [
+    &["key:W", "button:dpup", "axis:-lefty"],
+    &["key:S", "button:dpdown", "axis:+lefty"],
+    &["key:A", "button:dpleft", "axis:-leftx"],
+    &["key:D", "button:dpright", "axis:+leftx"],
+    &["key:G", "button:back"],
+    &["key:H", "button:start"],
+    &["key:J", "button:a"],
+    &["key:K", "button:b"],
+];
+
+const DEFAULT_PAD2: [&[&str]; 8] = [
+    &["key:Up", "button:dpup", "axis:-lefty"],
+    &["key:Down", "button:dpdown", "axis:+lefty"],
+    &["key:Left", "button:dpleft", "axis:-leftx"],
+    &["key:Right", "button:dpright", "axis:+leftx"],
+    &["key:Right Shift", "button:back"],
+    &["key:Return", "button:start"],
+    &["key:.", "button:a"],
+    &["key:/", "button:b"],
+];
+
+fn pad_bindings<S: AsRef<str>>(pad: usize, inputs: [&[S]; 8]) -> Result<Vec<Binding>, String> {
+    let mut bindings = vec![];
+    for ((_, button), inputs) in BUTTONS.iter().zip(inputs) {
+        for input in inputs {
+            bindings.push(Binding {
+                input: Input::parse(input.as_ref())?,
+                pad,
+                button: *button,
+            });
+        }
+    }
+    return Ok(bindings);
+}
+
+impl Default for Bindings {
+    /// W/A/S/D with G/H for select/start and J/K for A/B on the first pad,
+    /// the arrow keys, right shift, return, `.` and `/` on the second, and
+    /// the d-pad or left stick, back, start, A and B on gamepads.
+    fn default() -> Bindings {
+        let mut bindings = pad_bindings(0, DEFAULT_PAD1).unwrap();
+        bindings.extend(pad_bindings(1, DEFAULT_PAD2).unwrap());
+        return Bindings { bindings };
+    }
+}
+
+impl Bindings {
+    /// Parses a bindings file. Each `[pad1]`/`[pad2]` table lists the inputs
+    /// for each button; a pad without a table keeps the default bindings.
+    pub fn from_toml(text: &str) -> Result<Bindings, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        let defaults = Bindings::default();
+
+        let mut bindings = vec![];
+        for (pad, config) in [config.pad1, config.pad2].iter().enumerate() {
+            match config {
+                Some(config) => bindings.extend(pad_bindings(pad, config.inputs())?),
+                None => bindings.extend(
+                    defaults
+                        .bindings
+                        .iter()
+                        .filter(|binding| binding.pad == pad)
+                        .cloned(),
+                ),
+            }
+        }
+        return Ok(Bindings { bindings });
+    }
+
+    pub fn load(path: &Path) -> Result<Bindings, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        return Bindings::from_toml(&text).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config() {
+        let bindings = Bindings::from_toml(
+            r#"
+            [pad2]
+            a = ["key:Space", "button:x"]
+            left = ["axis:-rightx"]
+            "#,
+        )
+        .unwrap();
+
+        let pad2: Vec<&Binding> = bindings.bindings.iter().filter(|b| b.pad == 1).collect();
+        assert_eq!(
+            pad2,
+            [
+                &Binding {
+                    input: Input::Axis {
+                        axis: String::from("rightx"),
+                        positive: false
+                    },
+                    pad: 1,
+                    button: 0b00000100,
+                },
+                &Binding {
+                    input: Input::Key(String::from("Space")),
+                    pad: 1,
+                    button: 0b01000000,
+                },
+                &Binding {
+                    input: Input::Button(String::from("x")),
+                    pad: 1,
+                    button: 0b01000000,
+                },
+            ]
+        );
+        // The first pad wasn't configured, so it keeps its defaults
+        assert!(bindings.bindings.contains(&Binding {
+            input: Input::Key(String::from("W")),
+            pad: 0,
+            button: 0b00000001,
+        }));
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        assert!(Bindings::from_toml("[pad3]").is_err());
+        assert!(Bindings::from_toml("[pad1]\njump = [\"key:W\"]").is_err());
+        assert_eq!(
+            Bindings::from_toml("[pad1]\nup = [\"axis:lefty\"]")
+                .err()
+                .unwrap(),
+            "axis:lefty needs a + or - direction"
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use crate::cpu::{Controller, MEM_SIZE};
+
+/// I/O ports for the two pads. Each is a 16-bit register with the buttons in
+/// the low byte.
+pub const PAD1: u16 = 0xFFF0;
+pub const PAD2: u16 = 0xFFF2;
+
+/// The CPU's view of the address space. Words are little-endian and accesses
+/// running past 0xFFFF wrap around to 0x0000.
+pub trait Bus {
+    fn read8(&mut self, addr: u16) -> u8;
+
+    fn write8(&mut self, addr: u16, value: u8);
+
+    fn read16(&mut self, addr: u16) -> u16 {
+        return u16::from_le_bytes([self.read8(addr), self.read8(addr.wrapping_add(1))]);
+    }
+
+    fn write16(&mut self, addr: u16, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write8(addr, low);
+        self.write8(addr.wrapping_add(1), high);
+    }
+
+    /// Reads `buf.len()` bytes starting at `addr`, for sprites and palettes.
+    fn read(&mut self, addr: u16, buf: &mut [u8]) {
+        for (offset, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read8(addr.wrapping_add(offset as u16));
+        }
+    }
+}
+
+/// A memory access made by an instruction, recorded for watchpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct MemAccess {
+    pub addr: u16,
+    pub len: u16,
+    pub write: bool,
+}
+
+impl MemAccess {
+    /// Whether the access touches any address in `start..=end`. Accesses
+    /// running past 0xFFFF wrap around to 0x0000, as the bus does.
+    pub fn overlaps(&self, start: u16, end: u16) -> bool {
+        let (start, end) = (start as u32, end as u32);
+        let last = self.addr as u32 + self.len as u32 - 1;
+        if (self.addr as u32) <= end && last.min(MEM_SIZE as u32 - 1) >= start {
+            return true;
+        }
+        // The part that wrapped covers 0x0000 up to its last address
+        return last >= MEM_SIZE as u32 && last - MEM_SIZE as u32 >= start;
+    }
+}
+
+/// How many times an address has been read and written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AccessCount {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+/// 64 KB of RAM with the pad ports mapped over it. Accesses can be recorded
+/// per instruction for watchpoints and counted per address for profiling.
+pub struct Memory {
+    ram: [u8; MEM_SIZE],
+    controls: [Controller; 2],
+    record_accesses: bool,
+    accesses: Vec<MemAccess>,
+    counts: Option<Box<[AccessCount]>>,
+}
+
+impl Memory {
+    pub fn new(ram: &[u8; MEM_SIZE]) -> Memory {
+        return Memory {
+            ram: *ram,
+            controls: [0; 2],
+            record_accesses: false,
+            accesses: vec![],
+            counts: None,
+        };
+    }
+
+    /// The RAM, without the I/O ports mapped over it.
+    pub fn ram(&self) -> &[u8; MEM_SIZE] {
+        return &self.ram;
+    }
+
+    pub fn set_ram(&mut self, ram: &[u8; MEM_SIZE]) {
+        self.ram = *ram;
+    }
+
+    /// Reads a byte as the CPU would see it, without recording the access.
+    pub fn peek(&self, addr: u16) -> u8 {
+        return match addr {
+            PAD1 => self.controls[0],
+            PAD2 => self.controls[1],
+            _ if is_port(addr) => 0,
+            _ => self.ram[usize::from(addr)],
+        };
+    }
+
+    /// Fetches the instruction at `addr`, which isn't recorded as an access.
+    pub fn fetch(&self, addr: u16) -> [u8; 4] {
+        return [0, 1, 2, 3].map(|offset| self.ram[usize::from(addr.wrapping_add(offset))]);
+    }
+
+    pub fn controllers(&self) -> [Controller; 2] {
+        return self.controls;
+    }
+
+    pub fn set_controller(&mut self, pad: usize, state: Controller) {
+        self.controls[pad] = state;
+    }
+
+    pub fn set_record_accesses(&mut self, record: bool) {
+        self.record_accesses = record;
+        self.accesses.clear();
+    }
+
+    pub fn accesses(&self) -> &[MemAccess] {
+        return &self.accesses;
+    }
+
+    pub fn clear_accesses(&mut self) {
+        self.accesses.clear();
+    }
+
+    /// Starts counting accesses to each address from zero, or stops.
+    pub fn set_count_accesses(&mut self, count: bool) {
+        self.counts = match (count, self.counts.take()) {
+            (true, Some(counts)) => Some(counts),
+            (true, None) => Some(vec![AccessCount::default(); MEM_SIZE].into_boxed_slice()),
+            (false, _) => None,
+        };
+    }
+
+    /// Accesses made to each address since counting started.
+    pub fn access_counts(&self) -> Option<&[AccessCount]> {
+        return self.counts.as_deref();
+    }
+
+    fn access(&mut self, addr: u16, len: usize, write: bool) {
+        if self.record_accesses && len > 0 {
+            self.accesses.push(MemAccess {
+                addr,
+                len: len as u16,
+                write,
+            });
+        }
+        if let Some(counts) = &mut self.counts {
+            for offset in 0..len {
+                let count = &mut counts[usize::from(addr.wrapping_add(offset as u16))];
+                match write {
+                    true => count.writes += 1,
+                    false => count.reads += 1,
+                }
+            }
+        }
+    }
+}
+
+fn is_port(addr: u16) -> bool {
+    return (PAD1..PAD2 + 2).contains(&addr);
+}
+
+impl Bus for Memory {
+    fn read8(&mut self, addr: u16) -> u8 {
+        self.access(addr, 1, false);
+        return self.peek(addr);
+    }
+
+    fn write8(&mut self, addr: u16, value: u8) {
+        self.access(addr, 1, true);
+        if !is_port(addr) {
+            self.ram[usize::from(addr)] = value;
+        }
+    }
+
+    fn read16(&mut self, addr: u16) -> u16 {
+        self.access(addr, 2, false);
+        return u16::from_le_bytes([self.peek(addr), self.peek(addr.wrapping_add(1))]);
+    }
+
+    fn write16(&mut self, addr: u16, value: u16) {
+        self.access(addr, 2, true);
+        for (offset, byte) in value.to_le_bytes().into_iter().enumerate() {
+            let addr = addr.wrapping_add(offset as u16);
+            if !is_port(addr) {
+                self.ram[usize::from(addr)] = byte;
+            }
+        }
+    }
+
+    fn read(&mut self, addr: u16, buf: &mut [u8]) {
+        self.access(addr, buf.len(), false);
+        for (offset, byte) in buf.iter_mut().enumerate() {
+            *byte = self.peek(addr.wrapping_add(offset as u16));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_are_read_only_ports() {
+        let mut memory = Memory::new(&[0; MEM_SIZE]);
+        memory.set_controller(0, 0x41);
+        memory.set_controller(1, 0x28);
+        memory.write16(PAD1, 0xFFFF);
+
+        assert_eq!(memory.read16(PAD1), 0x0041);
+        assert_eq!(memory.read16(PAD2), 0x0028);
+        assert_eq!(memory.ram()[usize::from(PAD1)], 0);
+    }
+
+    #[test]
+    fn words_wrap_at_the_top_of_memory() {
+        let mut memory = Memory::new(&[0; MEM_SIZE]);
+        memory.write16(0xFFFF, 0x1234);
+        assert_eq!(memory.ram()[0xFFFF], 0x34);
+        assert_eq!(memory.ram()[0x0000], 0x12);
+        assert_eq!(memory.read16(0xFFFF), 0x1234);
+    }
+
+    #[test]
+    fn accesses_overlap_across_the_wrap() {
+        let mut memory = Memory::new(&[0; MEM_SIZE]);
+        memory.set_record_accesses(true);
+        memory.read16(0xFFFF);
+        memory.write16(0xFFFE, 0);
+
+        let accesses = memory.accesses();
+        assert!(accesses[0].overlaps(0x0000, 0x0000));
+        assert!(accesses[0].overlaps(0xFFFF, 0xFFFF));
+        assert!(!accesses[0].overlaps(0x0001, 0xFFFE));
+        assert!(!accesses[1].overlaps(0x0000, 0x0000));
+        assert!(accesses[1].overlaps(0xFFFF, 0xFFFF));
+    }
+
+    #[test]
+    fn records_and_counts_accesses() {
+        let mut memory = Memory::new(&[0; MEM_SIZE]);
+        memory.set_record_accesses(true);
+        memory.set_count_accesses(true);
+        memory.write16(0x1000, 1);
+        memory.read16(0x1001);
+        let mut sprite = [0; 8];
+        memory.read(0x0FFC, &mut sprite);
+
+        let accesses = memory.accesses();
+        assert_eq!(accesses.len(), 3);
+        assert!(accesses[0].write && accesses[0].overlaps(0x1001, 0x1001));
+        assert_eq!(accesses[2].len, 8);
+
+        let counts = memory.access_counts().unwrap();
+        assert_eq!(
+            counts[0x1001],
+            AccessCount {
+                reads: 2,
+                writes: 1
+            }
+        );
+        assert_eq!(counts[0x1003].reads, 1);
+        assert_eq!(counts[0x1004].reads, 0);
+    }
+}
@@ -0,0 +1,237 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::{Machine, FPS, GRID_X_SIZE, GRID_Y_SIZE};
+
+/// A frame as GIF palette indices and the 16 colors they index.
+#[derive(PartialEq)]
+struct GifFrame {
+    indices: Vec<u8>,
+    palette: Vec<u8>,
+}
+
+enum Format {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        /// The last frame and the frame number it first showed on, held back
+        /// until a different one shows how long it lasted.
+        pending: Option<(GifFrame, u32)>,
+    },
+    Y4m(BufWriter<File>),
+}
+
+/// Records every frame at 320x240 and 60 fps, to an animated GIF or a raw
+/// Y4M video.
+pub struct Capture {
+    format: Format,
+    frames: u32,
+}
+
+/// When a frame starts, in the centiseconds GIF delays are counted in.
+fn centiseconds(frame: u32) -> u32 {
+    return (frame as u64 * 100 / FPS as u64) as u32;
+}
+
+/// Resolves a color to limited range BT.601 Y, Cb and Cr.
+fn to_ycbcr(color: u32) -> [u8; 3] {
+    let (r, g, b) = (
+        (color >> 16) as i32,
+        ((color >> 8) & 0xFF) as i32,
+        (color & 0xFF) as i32,
+    );
+    return [
+        (16 + (66 * r + 129 * g + 25 * b + 128) / 256) as u8,
+        (128 + (-38 * r - 74 * g + 112 * b + 128) / 256) as u8,
+        (128 + (112 * r - 94 * g - 18 * b + 128) / 256) as u8,
+    ];
+}
+
+/// The palette index each pixel shows, with transparent pixels showing the
+/// background color.
+fn indices(machine: &Machine) -> Vec<u8> {
+    let bg = machine.bgc();
+    return machine
+        .screen()
+        .iter()
+        .map(|px| if *px == 0 { bg } else { *px })
+        .collect();
+}
+
+impl Capture {
+    /// Starts recording to `path`, as Y4M if the extension is `.y4m` and GIF
+    /// otherwise.
+    pub fn create(path: &Path) -> Result<Capture, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+
+        let is_y4m = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("y4m"));
+
+        let format = if is_y4m {
+            // 4:4:4 so single pixels keep their color
+            writeln!(
+                writer,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                GRID_X_SIZE, GRID_Y_SIZE, FPS
+            )
+            .map_err(|e| e.to_string())?;
+            Format::Y4m(writer)
+        } else {
+            let mut encoder =
+                gif::Encoder::new(writer, GRID_X_SIZE as u16, GRID_Y_SIZE as u16, &[])
+                    .map_err(|e| e.to_string())?;
+            encoder
+                .set_repeat(gif::Repeat::Infinite)
+                .map_err(|e| e.to_string())?;
+            Format::Gif {
+                encoder,
+                pending: None,
+            }
+        };
+        return Ok(Capture { format, frames: 0 });
+    }
+
+    /// Frames recorded so far.
+    pub fn frames(&self) -> u32 {
+        return self.frames;
+    }
+
+    pub fn add_frame(&mut self, machine: &Machine) -> Result<(), String> {
+        let frame = self.frames;
+        self.frames += 1;
+        match &mut self.format {
+            Format::Gif { encoder, pending } => {
+                let gif_frame = GifFrame {
+                    indices: indices(machine),
+                    palette: machine
+                        .palette()
+                        .iter()
+                        .flat_map(|color| [(color >> 16) as u8, (color >> 8) as u8, *color as u8])
+                        .collect(),
+                };
+                // Runs of identical frames become one longer frame
+                if pending.as_ref().is_some_and(|(last, _)| *last == gif_frame) {
+                    return Ok(());
+                }
+                if let Some((last, start)) = pending.take() {
+                    write_gif_frame(encoder, &last, centiseconds(frame) - centiseconds(start))?;
+                }
+                *pending = Some((gif_frame, frame));
+            }
+            Format::Y4m(writer) => {
+                let colors = machine.palette().map(to_ycbcr);
+                let mut planes: [Vec<u8>; 3] = Default::default();
+                for index in indices(machine) {
+                    for (plane, value) in planes.iter_mut().zip(colors[index as usize]) {
+                        plane.push(value);
+                    }
+                }
+                let mut data = b"FRAME\n".to_vec();
+                data.extend(planes.concat());
+                writer.write_all(&data).map_err(|e| e.to_string())?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Writes out the last frame and finishes the file.
+    pub fn finish(self) -> Result<(), String> {
+        match self.format {
+            Format::Gif {
+                mut encoder,
+                pending,
+            } => {
+                if let Some((last, start)) = pending {
+                    let delay = centiseconds(self.frames) - centiseconds(start);
+                    write_gif_frame(&mut encoder, &last, delay)?;
+                }
+                encoder
+                    .into_inner()
+                    .and_then(|mut writer| writer.flush())
+                    .map_err(|e| e.to_string())?;
+            }
+            Format::Y4m(mut writer) => writer.flush().map_err(|e| e.to_string())?,
+        }
+        return Ok(());
+    }
+}
+
+/// Writes a frame shown for `delay` centiseconds, splitting it up if that's
+/// longer than a GIF delay can be.
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    frame: &GifFrame,
+    mut delay: u32,
+) -> Result<(), String> {
+    while delay > 0 {
+        let part = delay.min(u16::MAX as u32);
+        let gif_frame = gif::Frame {
+            width: GRID_X_SIZE as u16,
+            height: GRID_Y_SIZE as u16,
+            buffer: Cow::Borrowed(&frame.indices),
+            palette: Some(frame.palette.clone()),
+            delay: part as u16,
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&gif_frame).map_err(|e| e.to_string())?;
+        delay -= part;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &Path) {
+        // VBLNK; VBLNK; BGC 3; JMP 0x000C
+        let mut machine = Machine::with_program(&[
+            0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x03, 0x00, 0x10, 0x00,
+            0x0C, 0x00,
+        ]);
+        let mut capture = Capture::create(path).unwrap();
+        for _ in 0..5 {
+            machine.run_frame().unwrap();
+            capture.add_frame(&machine).unwrap();
+        }
+        assert_eq!(capture.frames(), 5);
+        capture.finish().unwrap();
+    }
+
+    #[test]
+    fn records_gif_and_y4m() {
+        let dir = std::env::temp_dir();
+        let gif_path = dir.join(format!("chip16-capture-{}.gif", std::process::id()));
+        record(&gif_path);
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(File::open(&gif_path).unwrap()).unwrap();
+        // Two black frames merged into one, then three of BGC 3
+        let mut delays = vec![];
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+            assert_eq!(frame.palette.as_ref().unwrap()[9..12], [0xBF, 0x32, 0x32]);
+        }
+        assert_eq!(delays, vec![3, 5]);
+        std::fs::remove_file(gif_path).unwrap();
+
+        let y4m_path = dir.join(format!("chip16-capture-{}.y4m", std::process::id()));
+        record(&y4m_path);
+        let y4m = std::fs::read(&y4m_path).unwrap();
+        let header = b"YUV4MPEG2 W320 H240 F60:1 Ip A1:1 C444\n";
+        assert!(y4m.starts_with(header));
+        assert_eq!(y4m.len(), header.len() + 5 * (6 + 320 * 240 * 3));
+        let last = &y4m[y4m.len() - 320 * 240 * 3..];
+        assert_eq!(
+            [last[0], last[320 * 240], last[2 * 320 * 240]],
+            to_ycbcr(0xBF3232)
+        );
+        assert_eq!(to_ycbcr(0x000000), [16, 128, 128]);
+        assert_eq!(to_ycbcr(0xFFFFFF), [235, 128, 128]);
+        std::fs::remove_file(y4m_path).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::cpu::opcode_spec;
+use crate::disasm::lookup;
+use crate::rom::{Rom, RomError};
+use crate::sound::NullSound;
+use crate::{frame, Machine};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Ran every frame without faulting.
+    Ok,
+    Fault,
+    /// The ROM file couldn't be loaded.
+    Error,
+}
+
+/// How one ROM fared running headless.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RomReport {
+    pub name: String,
+    /// The spec version from the header, as `major.minor`.
+    pub spec: Option<String>,
+    pub status: Status,
+    /// The fault or load error, if any.
+    pub error: Option<String>,
+    /// Frames completed before the run ended.
+    pub frames: u32,
+    /// Opcodes executed, in order.
+    pub opcodes: Vec<u8>,
+    /// CRC-32 of the final frame as RGB, in hex.
+    pub screen_hash: Option<String>,
+    /// Spec and CRC warnings raised while loading and running.
+    pub warnings: Vec<String>,
+}
+
+/// A compatibility run over a set of ROMs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    /// Frames each ROM was run for.
+    pub frames: u32,
+    pub roms: Vec<RomReport>,
+}
+
+/// Reads every `.c16` file in a zip archive or, recursively, a directory,
+/// sorted by their path inside it.
+pub fn read_pack(path: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let is_rom = |name: &str| name.to_ascii_lowercase().ends_with(".c16");
+    let mut roms = vec![];
+    if path.is_dir() {
+        let mut dirs = vec![path.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+            for entry in entries {
+                let entry_path = entry.map_err(|e| e.to_string())?.path();
+                let name = entry_path
+                    .strip_prefix(path)
+                    .unwrap_or(&entry_path)
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                if entry_path.is_dir() {
+                    dirs.push(entry_path);
+                } else if is_rom(&name) {
+                    let bytes = std::fs::read(&entry_path)
+                        .map_err(|e| format!("Failed to read {}: {}", entry_path.display(), e))?;
+                    roms.push((name, bytes));
+                }
+            }
+        }
+    } else {
+        let file =
+            File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let mut archive = zip::ZipArchive::new(file)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(|e| e.to_string())?;
+            if !entry.is_file() || !is_rom(entry.name()) {
+                continue;
+            }
+            let name = entry.name().to_string();
+            let mut bytes = vec![];
+            entry
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("Failed to read {}: {}", name, e))?;
+            roms.push((name, bytes));
+        }
+    }
+    roms.sort_by(|a, b| a.0.cmp(&b.0));
+    return Ok(roms);
+}
+
+/// Runs a ROM headless for `frames` frames with no input and a fixed RNG
+/// seed, stopping at the first fault.
+pub fn run_rom(name: &str, bytes: Vec<u8>, frames: u32) -> RomReport {
+    let mut report = RomReport {
+        name: name.to_string(),
+        spec: None,
+        status: Status::Ok,
+        error: None,
+        frames: 0,
+        opcodes: vec![],
+        screen_hash: None,
+        warnings: vec![],
+    };
+
+    // Several ROMs in the wild have a wrong CRC but run fine
+    let rom = match Rom::from_bytes(bytes.clone(), true) {
+        Err(error @ RomError::CrcMismatch { .. }) => {
+            report.warnings.push(error.to_string());
+            Rom::from_bytes(bytes, false)
+        }
+        result => result,
+    };
+    let rom = match rom {
+        Ok(rom) => rom,
+        Err(error) => {
+            report.status = Status::Error;
+            report.error = Some(error.to_string());
+            return report;
+        }
+    };
+    report.spec = rom
+        .spec_version()
+        .map(|(major, minor)| format!("{}.{}", major, minor));
+
+    let mut machine = Machine::from_rom(&rom, Box::new(NullSound));
+    machine.seed_rng(0);
+    for _ in 0..frames {
+        let result = machine.run_frame();
+        report.warnings.extend(machine.take_warnings());
+        if let Err((fault, _)) = result {
+            report.status = Status::Fault;
+            report.error = Some(fault.to_string());
+            break;
+        }
+        report.frames += 1;
+    }
+
+    report.opcodes = (0..=0xFF)
+        .filter(|opcode| machine.opcode_counts()[usize::from(*opcode)] > 0)
+        .collect();
+    report.screen_hash = Some(format!("{:08X}", crc32fast::hash(&frame::to_rgb(&machine))));
+    return report;
+}
+
+/// Runs every ROM, in order.
+pub fn run(roms: Vec<(String, Vec<u8>)>, frames: u32) -> Report {
+    return Report {
+        frames,
+        roms: roms
+            .into_iter()
+            .map(|(name, bytes)| run_rom(&name, bytes, frames))
+            .collect(),
+    };
+}
+
+impl Report {
+    /// How many ROMs executed each valid opcode.
+    pub fn coverage(&self) -> Vec<(u8, usize)> {
+        return (0..=0xFF)
+            .filter(|opcode| lookup(*opcode).is_some())
+            .map(|opcode| {
+                let roms = self
+                    .roms
+                    .iter()
+                    .filter(|rom| rom.opcodes.contains(&opcode))
+                    .count();
+                (opcode, roms)
+            })
+            .collect();
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("Reports always serialize");
+    }
+
+    /// A table of results per ROM followed by the opcode coverage.
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::from("# Chip16 compatibility\n\n");
+        let ok = self
+            .roms
+            .iter()
+            .filter(|rom| rom.status == Status::Ok)
+            .count();
+        let _ = writeln!(
+            out,
+            "{} of {} ROMs ran {} frames without faulting.\n",
+            ok,
+            self.roms.len(),
+            self.frames
+        );
+        let _ = writeln!(out, "| ROM | Spec | Result | Frames | Opcodes | Screen |");
+        let _ = writeln!(out, "| --- | --- | --- | --- | --- | --- |");
+        for rom in &self.roms {
+            let result = match (&rom.status, &rom.error) {
+                (Status::Ok, _) if rom.warnings.is_empty() => "ok".to_string(),
+                (Status::Ok, _) if rom.warnings.len() == 1 => "ok, 1 warning".to_string(),
+                (Status::Ok, _) => format!("ok, {} warnings", rom.warnings.len()),
+                (_, Some(error)) => error.clone(),
+                (_, None) => format!("{:?}", rom.status),
+            };
+            let _ = writeln!(
+                out,
+                "| {} | {} | {} | {} | {} | {} |",
+                rom.name.replace('|', "\\|"),
+                rom.spec.as_deref().unwrap_or("-"),
+                result,
+                rom.frames,
+                rom.opcodes.len(),
+                rom.screen_hash
+                    .as_ref()
+                    .map_or("-".to_string(), |hash| format!("`{}`", hash))
+            );
+        }
+
+        if self.roms.iter().any(|rom| !rom.warnings.is_empty()) {
+            let _ = writeln!(out, "\n## Warnings\n");
+            for rom in &self.roms {
+                for warning in &rom.warnings {
+                    let _ = writeln!(out, "- {}: {}", rom.name, warning);
+                }
+            }
+        }
+
+        let _ = writeln!(out, "\n## Opcode coverage\n");
+        let _ = writeln!(out, "| Opcode | Instruction | Spec | ROMs |");
+        let _ = writeln!(out, "| --- | --- | --- | --- |");
+        for (opcode, roms) in self.coverage() {
+            let mnemonic = lookup(opcode).map_or("db", |(mnemonic, _)| mnemonic);
+            let (major, minor) = opcode_spec(opcode);
+            let _ = writeln!(
+                out,
+                "| {:02X} | {} | {}.{} | {} |",
+                opcode, mnemonic, major, minor, roms
+            );
+        }
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_opcodes_faults_and_load_errors() {
+        // LDI r0, 5; CLS; VBLNK; JMP 0x0004
+        let looping = Rom::with_header(
+            (1, 1),
+            0,
+            vec![
+                0x20, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x10, 0x00,
+                0x04, 0x00,
+            ],
+        );
+        // LDI r0, 5; DIV r0, r1
+        let faulting = vec![0x20, 0x00, 0x05, 0x00, 0xA1, 0x10, 0x00, 0x00];
+        let report = run(
+            vec![
+                ("loop.c16".to_string(), looping.to_bytes()),
+                ("div.c16".to_string(), faulting),
+                ("short.c16".to_string(), b"CH16".to_vec()),
+            ],
+            3,
+        );
+
+        let rom = &report.roms[0];
+        assert_eq!(rom.status, Status::Ok);
+        assert_eq!(rom.spec.as_deref(), Some("1.1"));
+        assert_eq!(rom.frames, 3);
+        assert_eq!(rom.opcodes, vec![0x01, 0x02, 0x10, 0x20]);
+        assert!(rom.screen_hash.is_some());
+
+        let rom = &report.roms[1];
+        assert_eq!(rom.status, Status::Fault);
+        assert_eq!(rom.error.as_deref(), Some("Division by zero at 0x0004"));
+        assert_eq!(rom.frames, 0);
+        assert_eq!(rom.opcodes, vec![0x20, 0xA1]);
+
+        assert_eq!(report.roms[2].status, Status::Error);
+        assert_eq!(report.roms[2].screen_hash, None);
+
+        let coverage = report.coverage();
+        assert!(coverage.contains(&(0x20, 2)));
+        assert!(coverage.contains(&(0xA0, 0)));
+
+        let markdown = report.to_markdown();
+        assert!(markdown.contains("1 of 3 ROMs ran 3 frames without faulting."));
+        assert!(markdown.contains("| div.c16 | - | Division by zero at 0x0004 | 0 | 2 |"));
+        assert!(markdown.contains("| 20 | ldi | 0.7 |"), "{}", markdown);
+        assert!(report.to_json().contains("\"status\": \"fault\""));
+    }
+}
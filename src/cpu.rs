@@ -1,42 +1,77 @@
-use std::fs::File;
-use std::thread;
-use std::time::{Duration, Instant};
-
-use rand::Rng;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::EventPump;
-
-use crate::audio::AudioState;
-use crate::renderer::Renderer;
-use crate::{audio, FRAME_CYCLES};
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::bus::{AccessCount, Bus, MemAccess, Memory};
+use crate::disasm::disassemble;
+use crate::fault::{Fault, HISTORY_LEN};
+use crate::rom::Rom;
+use crate::sound::Sound;
+use crate::state::State;
+use crate::timing::{Clock, Timing};
+use crate::trace::Tracer;
+use byteorder::{ReadBytesExt, LE};
 
 const SCREEN_SIZE_X: u16 = 320;
 const SCREEN_SIZE_Y: u16 = 240;
-const SCREEN_BUF_SIZE: usize = SCREEN_SIZE_X as usize * SCREEN_SIZE_Y as usize;
-const FRAME_DURATION: Duration = Duration::new(0, 1_000_000_000u32 / 60);
-
-type Instruction = [u8; 4];
-
-fn hhll(instruction: &Instruction) -> u16 {
+pub const SCREEN_BUF_SIZE: usize = SCREEN_SIZE_X as usize * SCREEN_SIZE_Y as usize;
+pub const MEM_SIZE: usize = 65536;
+/// The stack grows up from STACK_START and is STACK_SIZE bytes, ending just
+/// before the I/O ports.
+pub const STACK_START: u16 = 0xFDF0;
+pub const STACK_SIZE: usize = 0x200;
+
+/// The newest spec revision, which ROMs without a header are run as.
+pub const LATEST_SPEC: (u8, u8) = (1, 3);
+
+/// Opcodes added by each revision of the spec after 0.7, as listed in the
+/// revision history of the specification at https://github.com/chip16/chip16.
+/// 1.2 only clarified behaviour and added no instructions.
+const ADDED_OPCODES: [((u8, u8), &[u8]); 3] = [
+    // CALL RX, Cx, PUSHALL, POPALL, PUSHF, POPF
+    ((1, 0), &[0x17, 0x18, 0xC2, 0xC3, 0xC4, 0xC5]),
+    // SNP, SNG, PAL
+    ((1, 1), &[0x0D, 0x0E, 0xD0, 0xD1]),
+    // MOD, REM, NOT, NEG
+    (
+        (1, 3),
+        &[
+            0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xE0, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5,
+        ],
+    ),
+];
+
+/// The spec revision that added an opcode. Anything not added later is
+/// assumed to date from 0.7.
+pub fn opcode_spec(opcode: u8) -> (u8, u8) {
+    return ADDED_OPCODES
+        .iter()
+        .find(|(_, opcodes)| opcodes.contains(&opcode))
+        .map_or((0, 7), |(spec, _)| *spec);
+}
+
+pub(crate) type Instruction = [u8; 4];
+
+pub(crate) fn hhll(instruction: &Instruction) -> u16 {
     return (&instruction[2..4])
         .read_u16::<LE>()
         .expect("Failed to read instruction hhll");
 }
 
-fn rx_ry(instruction: &Instruction) -> (usize, usize) {
+pub(crate) fn rx_ry(instruction: &Instruction) -> (usize, usize) {
     return (
         usize::from(instruction[1] & 0xF),
         usize::from((instruction[1] & 0xF0) >> 4),
     );
 }
 
-fn rx(instruction: &Instruction) -> usize {
+pub(crate) fn rx(instruction: &Instruction) -> usize {
     return usize::from(instruction[1] & 0xF);
 }
 
-fn rx_ry_rz(instruction: &Instruction) -> (usize, usize, usize) {
+pub(crate) fn rx_ry_rz(instruction: &Instruction) -> (usize, usize, usize) {
     return (
         usize::from(instruction[1] & 0xF),
         usize::from((instruction[1] & 0xF0) >> 4),
@@ -44,68 +79,60 @@ fn rx_ry_rz(instruction: &Instruction) -> (usize, usize, usize) {
     );
 }
 
-fn n(instruction: &Instruction) -> u8 {
+pub(crate) fn n(instruction: &Instruction) -> u8 {
     return instruction[2] & 0xF;
 }
 
-fn nop(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("noop");
+/// The address of the instruction being executed, as PC has already moved
+/// past it.
+fn instruction_pc(state: &Machine) -> u16 {
+    return state.pc.wrapping_sub(4);
+}
+
+fn nop(_state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
     Ok(())
 }
 
-fn error(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("Invalid command {:#02X?}", instruction[0]);
-    Err(String::from("Invalid command"))
+fn error(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
+    Err(Fault::InvalidOpcode {
+        pc: instruction_pc(state),
+        op: instruction[0],
+    })
 }
 
-fn cls(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("cls");
+fn cls(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
     state.graphics.bg = 0;
     state.screen.iter_mut().for_each(|m| *m = 0);
     Ok(())
 }
 
-fn vblnk(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    //instr_dbg_println!("vblnk");
-    // Skip to frame render if we hit vblank
-    if !state.vblnk {
-        //state.cycles = FRAME_CYCLES
-        state.pc -= 4;
-    }
+fn vblnk(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
+    // Rather than spinning until the frame ends, skip straight to it
+    state.vblnk = true;
     Ok(())
 }
 
-fn bgc_n(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    //instr_dbg_println!("bgc_n");
+fn bgc_n(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     state.graphics.bg = instruction[2] & 0xF;
-    instr_dbg_println!("Set bg to {:#02X?}", state.graphics.bg);
     Ok(())
 }
 
-fn spr_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    //instr_dbg_println!("spr_hhll");
+fn spr_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     state.graphics.spritew = instruction[2];
     state.graphics.spriteh = instruction[3];
-    instr_dbg_println!(
-        "Set spriteh: {:#02X?}, spritew: {:#02X?}",
-        state.graphics.spriteh,
-        state.graphics.spritew
-    );
 
     Ok(())
 }
 
-fn drw_rx_ry_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
+fn drw_rx_ry_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     let sprite_addr = hhll(instruction);
 
-    //instr_dbg_println!("drw_r{:#02X}_r{:#02X}_{:#04X}", rx, ry, sprite_addr);
     draw_sprite(state, state.registers[rx], state.registers[ry], sprite_addr);
     Ok(())
 }
 
-fn drw_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    //instr_dbg_println!("drw_rx_ry_rz");
+fn drw_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     draw_sprite(
         state,
@@ -116,64 +143,47 @@ fn drw_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String
     Ok(())
 }
 
-fn rnd_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("rnd_rx_hhll");
+fn rnd_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let max = (hhll(instruction) as u32) + 1;
-    state.registers[rx(instruction)] = (rand::thread_rng().gen_range(0..(max)) & 0xFFFF) as i16;
-    instr_dbg_println!(
-        "Generated random number {}, from 0 to {}",
-        state.registers[rx(instruction)],
-        max - 1
-    );
+    state.registers[rx(instruction)] = (state.rng.gen_range(0..(max)) & 0xFFFF) as i16;
     Ok(())
 }
-fn flip(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("flip");
+fn flip(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let flip = hhll(instruction) >> 0x8;
     state.graphics.vflip = (flip & 0x1) != 0;
     state.graphics.hflip = ((flip >> 1) & 0x1) != 0;
     Ok(())
 }
-fn snd0(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("snd0");
-    state.audio_state.clear();
+fn snd0(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
+    state.audio.clear();
     Ok(())
 }
-fn snd1_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("snd1_hhll");
-    //dbg_println!("Playing for {} ms", hhll(instruction));
-
-    state.audio_state.play_sound(500, hhll(instruction));
+fn snd1_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
+    state.audio.play_sound(500, hhll(instruction));
 
     Ok(())
 }
-fn snd2_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("snd2_hhll");
-    state.audio_state.play_sound(1000, hhll(instruction));
+fn snd2_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
+    state.audio.play_sound(1000, hhll(instruction));
 
     Ok(())
 }
-fn snd3_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("snd3_hhll");
-    state.audio_state.play_sound(1500, hhll(instruction));
+fn snd3_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
+    state.audio.play_sound(1500, hhll(instruction));
     Ok(())
 }
 
-fn snp_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("snp_rx_hhll");
+fn snp_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
     let addr = (state.registers[rx] as usize) & 0xFFFF;
     let freq = load_mem(state, addr);
-    instr_dbg_println!("addr: {:#02X}, hz: {}", addr, freq);
-    instr_dbg_println!("freq: {}hz", freq);
-    state.audio_state.play_custom_sound(freq, hhll(instruction));
+    state.audio.play_custom_sound(freq, hhll(instruction));
 
-    state.audio_state.start();
+    state.audio.start();
     Ok(())
 }
 
-fn sng_ad_vtsr(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("sng_ad_vtsr");
+fn sng_ad_vtsr(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let attack = (instruction[1] & 0xF0) >> 4;
     let decay = instruction[1] & 0xF;
     let sustain = (instruction[2] & 0xF0) >> 4;
@@ -182,166 +192,113 @@ fn sng_ad_vtsr(state: &mut CPU, instruction: &Instruction) -> Result<(), String>
     let wave_type = instruction[3] & 0xF;
 
     state
-        .audio_state
-        .set_params(attack, decay, sustain, release, volume, wave_type)?;
-
-    instr_dbg_println!(
-        "attack: {}, decay: {}, sustain: {}, release: {}, volume: {}, wave_type: {}",
-        attack,
-        decay,
-        sustain,
-        release,
-        volume,
-        wave_type
-    );
+        .audio
+        .set_params(attack, decay, sustain, release, volume, wave_type)
+        .map_err(|_| Fault::InvalidWaveform {
+            pc: instruction_pc(state),
+            wave: wave_type,
+        })?;
+
     Ok(())
 }
 
-fn jmp_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    //instr_dbg_println!("jmp_hhll");
+fn jmp_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     state.pc = hhll(instruction);
-    //instr_dbg_println!("Set pc to {:#02X?}", state.pc);
 
     Ok(())
 }
 
-fn jmc_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("jmc_hhll");
+fn jmc_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     if state.flags.C {
         state.pc = hhll(instruction);
     }
     Ok(())
 }
 
-fn jx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!(
-        "j{:#02X?}_{:#04X?}",
-        instruction[1] & 0xF,
-        hhll(instruction)
-    );
-    instr_dbg_println!("{:?}", state.flags);
-    instr_dbg_println!("{:?}", test_cond(state, instruction)?);
+fn jx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     if test_cond(state, instruction)? {
         state.pc = hhll(instruction);
-        instr_dbg_println!("Set pc to {:#02X?}", state.pc);
     }
     Ok(())
 }
 
-fn jme_rx_ry_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
+fn jme_rx_ry_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    instr_dbg_println!("jme_r{:#02X}_r{:#02X}_{:#04X?}", rx, ry, hhll(instruction));
-
     if state.registers[rx] == state.registers[ry] {
         state.pc = hhll(instruction);
     }
     Ok(())
 }
 
-fn call_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("call_hhll");
-    store_mem(state, state.pc, state.sp);
-    state.sp += 2;
-    state.pc = hhll(instruction);
-    state.stack.push(state.pc);
-    instr_dbg_println!("Set pc to {:#02X?}", state.pc);
-    Ok(())
+fn call_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
+    call(state, hhll(instruction))
 }
-fn ret(state: &mut CPU, _instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("ret");
+fn ret(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
+    check_pop(state)?;
     state.sp -= 2;
     let addr = state.sp;
     state.pc = load_mem(state, addr);
-    state.stack.pop();
     Ok(())
 }
-fn jmp_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("jmp_rx");
+fn jmp_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.pc = state.registers[rx] as u16;
     Ok(())
 }
-fn cx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!(
-        "c{:#02X?}_{:#04X?}",
-        instruction[1] & 0xF,
-        hhll(instruction)
-    );
+fn cx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     if test_cond(state, instruction)? {
-        store_mem(state, state.pc, state.sp);
-        state.sp += 2;
-        state.pc = hhll(instruction);
-        state.stack.push(state.pc);
-        instr_dbg_println!("Set pc to {:#02X?}", state.pc);
+        call(state, hhll(instruction))?;
     }
     Ok(())
 }
-fn call_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("call_rx");
+fn call_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
-    store_mem(state, state.pc, state.sp);
-    state.sp += 2;
-    state.pc = state.registers[rx] as u16;
-    state.stack.push(state.pc);
-    instr_dbg_println!("Set pc to {:#02X?}", state.pc);
-    Ok(())
+    call(state, state.registers[rx] as u16)
 }
-fn ldi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("ldi_rx_hhll");
+fn ldi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.registers[rx] = hhll(instruction) as i16;
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn ldi_sp_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("ldi_sp_hhll");
-    state.sp = hhll(instruction) as usize;
+fn ldi_sp_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
+    let sp = hhll(instruction);
+    // Some programs put their stack elsewhere, so pointing SP outside the
+    // stack moves it there
+    if !stack_range(state).contains(&usize::from(sp)) {
+        state.stack_base = sp;
+    }
+    state.sp = usize::from(sp);
     Ok(())
 }
 
-fn ldm_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("ldm_rx_hhll");
+fn ldm_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.registers[rx] = load_mem(state, hhll(instruction) as usize) as i16;
 
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
-
     Ok(())
 }
 
-fn ldm_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("ldm_rx_ry");
+fn ldm_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] = load_mem(state, state.registers[ry] as usize) as i16;
 
-    instr_dbg_println!(
-        "Set register {:#02X?} to [register {:#02X?}] ([{:#02X?}]): {:#02X?}",
-        rx,
-        ry,
-        state.registers[ry],
-        state.registers[rx]
-    );
     Ok(())
 }
-fn mov_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("mov_rx_ry");
+fn mov_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] = state.registers[ry];
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
 
-fn stm_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("stm_rx_hhll");
+fn stm_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let addr = hhll(instruction) as usize;
     store_mem(state, state.registers[rx] as u16, addr);
     Ok(())
 }
 
-fn stm_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("stm_rx_ry");
+fn stm_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     store_mem(
         state,
@@ -351,437 +308,325 @@ fn stm_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
     Ok(())
 }
 
-fn addi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("addi_rx_hhll");
-    instr_dbg_println!(
-        "{:x}: {:x} {:x} {:x} {:x}",
-        state.pc,
-        instruction[0],
-        instruction[1],
-        instruction[2],
-        instruction[3]
-    );
-
+fn addi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = hhll(instruction) as i16;
-    instr_dbg_println!("Adding {} to {}", state.registers[rx], val);
     state.registers[rx] = op_add(state, state.registers[rx], val);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
-    instr_dbg_println!("Flags: {:?}", state.flags);
     Ok(())
 }
-fn add_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("add_rx_ry");
+fn add_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
     state.registers[rx] = op_add(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
 
     Ok(())
 }
-fn add_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("add_rx_ry_rz");
+fn add_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     state.registers[rz] = op_add(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rz, state.registers[rz]);
     Ok(())
 }
-fn subi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("subi_rx_hhll");
+fn subi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = ((u16::from(instruction[3]) << 0x8) + u16::from(instruction[2])) as i16;
 
     state.registers[rx] = op_sub(state, state.registers[rx], val);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn sub_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("sub_rx_ry");
+fn sub_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] = op_sub(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn sub_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("sub_rx_ry_rz");
+fn sub_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     state.registers[rz] = op_sub(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rz, state.registers[rz]);
 
     Ok(())
 }
-fn cmpi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("cmpi_rx_hhll");
+fn cmpi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = ((u16::from(instruction[3]) << 0x8) + u16::from(instruction[2])) as i16;
     op_sub(state, state.registers[rx], val);
-    instr_dbg_println!("{:?}", state.flags);
     Ok(())
 }
-fn cmp_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("cmp_rx_ry");
+fn cmp_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     op_sub(state, state.registers[rx], state.registers[ry]);
     Ok(())
 }
-fn andi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("andi_rx_hhll");
+fn andi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = ((u16::from(instruction[3]) << 0x8) + u16::from(instruction[2])) as i16;
     and_flags(state, state.registers[rx], val);
     state.registers[rx] &= val;
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
 
     Ok(())
 }
-fn and_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("and_rx_ry");
+fn and_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     and_flags(state, state.registers[rx], state.registers[ry]);
     state.registers[rx] &= state.registers[ry];
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
 
     Ok(())
 }
-fn and_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("and_rx_ry_rz");
+fn and_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     and_flags(state, state.registers[rx], state.registers[ry]);
     state.registers[rz] = state.registers[rx] & state.registers[ry];
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rz]);
 
     Ok(())
 }
-fn tsti_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("tsti_rx_hhll");
+fn tsti_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     and_flags(state, state.registers[rx], hhll(instruction) as i16);
 
     Ok(())
 }
-fn tst_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("tsti_rx_hhll");
+fn tst_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     and_flags(state, state.registers[rx], state.registers[ry]);
 
     Ok(())
 }
-fn ori_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("ori_rx_hhll");
+fn ori_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = ((u16::from(instruction[3]) << 0x8) + u16::from(instruction[2])) as i16;
     state.registers[rx] |= val;
     set_flags_z_n(state, state.registers[rx]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn or_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("or_rx_ry");
+fn or_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] |= state.registers[ry];
     set_flags_z_n(state, state.registers[rx]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn or_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("or_rx_ry_rz");
+fn or_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     state.registers[rz] = state.registers[rx] | state.registers[ry];
     set_flags_z_n(state, state.registers[rz]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rz]);
     Ok(())
 }
-fn xori_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("xori_rx_hhll");
+fn xori_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = ((u16::from(instruction[3]) << 0x8) + u16::from(instruction[2])) as i16;
     state.registers[rx] ^= val;
     set_flags_z_n(state, state.registers[rx]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn xor_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("xor_rx_ry");
+fn xor_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] ^= state.registers[ry];
     set_flags_z_n(state, state.registers[rx]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn xor_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("xor_rx_ry_rz");
+fn xor_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     state.registers[rz] = state.registers[rx] ^ state.registers[ry];
     set_flags_z_n(state, state.registers[rz]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rz]);
     Ok(())
 }
-fn muli_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("muli_rx_hhll");
+fn muli_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = hhll(instruction) as i16;
-    instr_dbg_println!("Multiplying {} * {}", state.registers[rx], val);
     state.registers[rx] = op_mul(state, state.registers[rx], val);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
 
     Ok(())
 }
-fn mul_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("mul_rx_ry");
+fn mul_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
     state.registers[rx] = op_mul(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
     Ok(())
 }
-fn mul_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("mul_rx_ry_rz");
+fn mul_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
     state.registers[rz] = op_mul(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rz, state.registers[rz]);
     Ok(())
 }
-fn divi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("divi_rx_hhll");
+fn divi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = hhll(instruction) as i16;
-    instr_dbg_println!("Dividing {} / {}", state.registers[rx], val);
-    state.registers[rx] = op_div(state, state.registers[rx], val);
-    instr_dbg_println!("{:?}", state.flags);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
+    state.registers[rx] = op_div(state, state.registers[rx], val)?;
 
     Ok(())
 }
-fn div_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("div_rx_ry");
+fn div_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    state.registers[rx] = op_div(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
+    state.registers[rx] = op_div(state, state.registers[rx], state.registers[ry])?;
     Ok(())
 }
-fn div_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("div_rx_ry_rz");
+fn div_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
-    state.registers[rz] = op_div(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rz, state.registers[rz]);
+    state.registers[rz] = op_div(state, state.registers[rx], state.registers[ry])?;
     Ok(())
 }
-fn modi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("modi_rx_hhll");
+fn modi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = hhll(instruction) as i16;
-    instr_dbg_println!("{} mod {}", state.registers[rx], val);
-    state.registers[rx] = op_mod(state, state.registers[rx], val);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
+    state.registers[rx] = op_mod(state, state.registers[rx], val)?;
 
     Ok(())
 }
-fn mod_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("mod_rx_ry");
+fn mod_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    instr_dbg_println!("{} mod {}", state.registers[rx], state.registers[ry]);
-    state.registers[rx] = op_mod(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
+    state.registers[rx] = op_mod(state, state.registers[rx], state.registers[ry])?;
     Ok(())
 }
-fn mod_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("mod_rx_ry_rz");
+fn mod_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
-    instr_dbg_println!("{} mod {}", state.registers[rx], state.registers[ry]);
-    state.registers[rz] = op_mod(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rz, state.registers[rz]);
+    state.registers[rz] = op_mod(state, state.registers[rx], state.registers[ry])?;
     Ok(())
 }
-fn remi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("remi_rx_hhll");
+fn remi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     let val = hhll(instruction) as i16;
-    state.registers[rx] = op_rem(state, state.registers[rx], val);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
+    state.registers[rx] = op_rem(state, state.registers[rx], val)?;
 
     Ok(())
 }
-fn rem_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("rem_rx_ry");
+fn rem_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    state.registers[rx] = op_rem(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rx, state.registers[rx]);
+    state.registers[rx] = op_rem(state, state.registers[rx], state.registers[ry])?;
     Ok(())
 }
-fn rem_rx_ry_rz(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("rem_rx_ry_rz");
+fn rem_rx_ry_rz(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry, rz) = rx_ry_rz(instruction);
-    state.registers[rz] = op_rem(state, state.registers[rx], state.registers[ry]);
-    instr_dbg_println!("Set register {:#02X?} to {:#02X?}", rz, state.registers[rz]);
+    state.registers[rz] = op_rem(state, state.registers[rx], state.registers[ry])?;
     Ok(())
 }
-fn shl_rx_n(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("shl_rx_n");
+fn shl_rx_n(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
     let n = n(instruction);
-    state.registers[rx] = state.registers[rx] << n;
+    state.registers[rx] <<= n;
     set_flags_z_n(state, state.registers[rx]);
     Ok(())
 }
-fn shr_rx_n(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("shr_rx_n");
+fn shr_rx_n(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
     let n = n(instruction);
     state.registers[rx] = (state.registers[rx] as u16 >> n) as i16;
     set_flags_z_n(state, state.registers[rx]);
     Ok(())
 }
-fn sar_rx_n(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("shr_rx_n");
+fn sar_rx_n(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
     let n = n(instruction);
-    state.registers[rx] = state.registers[rx] >> n;
+    state.registers[rx] >>= n;
     set_flags_z_n(state, state.registers[rx]);
     Ok(())
 }
-fn shl_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("shl_rx_ry");
+/// Register shifts only use the low four bits of the count, as larger counts
+/// would shift everything out.
+fn shift_count(state: &Machine, ry: usize) -> u32 {
+    return (state.registers[ry] & 0xF) as u32;
+}
+fn shl_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    state.registers[rx] = state.registers[rx] << state.registers[ry];
+    state.registers[rx] <<= shift_count(state, ry);
     set_flags_z_n(state, state.registers[rx]);
     Ok(())
 }
-fn shr_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("shr_rx_ry");
+fn shr_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    state.registers[rx] = (state.registers[rx] as u16 >> state.registers[ry]) as i16;
+    state.registers[rx] = (state.registers[rx] as u16 >> shift_count(state, ry)) as i16;
     set_flags_z_n(state, state.registers[rx]);
 
     Ok(())
 }
-fn sar_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("sar_rx_ry");
+fn sar_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
 
-    state.registers[rx] = state.registers[rx] >> state.registers[ry];
+    state.registers[rx] >>= shift_count(state, ry);
     set_flags_z_n(state, state.registers[rx]);
     Ok(())
 }
-fn push_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("push_rx");
+fn push_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
-    push_reg(state, rx);
-    Ok(())
+    push_reg(state, rx)
 }
-fn pop_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("pop_rx");
+fn pop_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let rx = rx(instruction);
-    pop_reg(state, rx);
-    Ok(())
+    pop_reg(state, rx)
 }
-fn pushall(state: &mut CPU, _instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("pushall");
+fn pushall(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
     for r in 0..(state.registers.len()) {
-        push_reg(state, r);
+        push_reg(state, r)?;
     }
     Ok(())
 }
-fn popall(state: &mut CPU, _instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("popall");
-
+fn popall(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
     for r in 0..(state.registers.len()) {
         let reg = state.registers.len() - 1 - r;
-        pop_reg(state, reg);
+        pop_reg(state, reg)?;
     }
     Ok(())
 }
-fn pushf(state: &mut CPU, _instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("pushf");
-    let mut val = 0;
-    if state.flags.C {
-        val |= 0b00000010;
-    }
-    if state.flags.Z {
-        val |= 0b00000100;
-    }
-    if state.flags.O {
-        val |= 0b01000000;
-    }
-    if state.flags.N {
-        val |= 0b10000000;
-    }
-    state.mem[state.sp] = val;
+fn pushf(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
+    check_push(state)?;
+    let flags = state.flags.to_byte();
+    state.bus.write8(state.sp as u16, flags);
 
     state.sp += 2;
     Ok(())
 }
-fn popf(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("popf");
+fn popf(state: &mut Machine, _instruction: &Instruction) -> Result<(), Fault> {
+    check_pop(state)?;
     state.sp -= 2;
-    let val = state.mem[state.sp];
-
-    state.flags.C = 0b00000010 & val > 0;
-    state.flags.Z = 0b00000100 & val > 0;
-    state.flags.O = 0b01000000 & val > 0;
-    state.flags.N = 0b10000000 & val > 0;
+    state.flags = FLAGS::from_byte(state.bus.read8(state.sp as u16));
 
     Ok(())
 }
-fn pal_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("pal_hhll");
+fn pal_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let addr = hhll(instruction);
-    instr_dbg_println!("Loading palette from {:X}", addr);
     load_palette(state, addr as usize);
     Ok(())
 }
-fn pal_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("pal_rx");
+fn pal_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let addr = (state.registers[rx(instruction)]) as usize & 0xFFFF;
-    instr_dbg_println!("Loading palette from {:02X}", addr);
-    load_palette(state, addr as usize);
+    load_palette(state, addr);
     Ok(())
 }
-fn noti_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("noti_rx_hhll");
+fn noti_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.registers[rx] = op_not(state, hhll(instruction) as i16);
     Ok(())
 }
-fn not_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("not_rx");
+fn not_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.registers[rx] = op_not(state, state.registers[rx]);
     Ok(())
 }
-fn not_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("not_rx_ry");
+fn not_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] = op_not(state, state.registers[ry]);
     Ok(())
 }
-fn negi_rx_hhll(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("negi_rx_hhll");
+fn negi_rx_hhll(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.registers[rx] = op_neg(state, hhll(instruction) as i16);
     Ok(())
 }
 
-fn neg_rx(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("neg_rx");
+fn neg_rx(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, _) = rx_ry(instruction);
     state.registers[rx] = op_neg(state, state.registers[rx]);
     Ok(())
 }
-fn neg_rx_ry(state: &mut CPU, instruction: &Instruction) -> Result<(), String> {
-    instr_dbg_println!("neg_rx_ry");
+fn neg_rx_ry(state: &mut Machine, instruction: &Instruction) -> Result<(), Fault> {
     let (rx, ry) = rx_ry(instruction);
     state.registers[rx] = op_neg(state, state.registers[ry]);
     Ok(())
 }
 
-fn test_cond(state: &mut CPU, instruction: &Instruction) -> Result<bool, String> {
+fn test_cond(state: &mut Machine, instruction: &Instruction) -> Result<bool, Fault> {
     let cond = instruction[1] & 0xF;
-    instr_dbg_println!("Testing cond {:X}", cond);
 
     match cond {
         0x0 => Ok(state.flags.Z),                                    // Z == 1
@@ -799,12 +644,14 @@ fn test_cond(state: &mut CPU, instruction: &Instruction) -> Result<bool, String>
         0xC => Ok(state.flags.O == state.flags.N),                   // O == N
         0xD => Ok(state.flags.O != state.flags.N),                   // O != N
         0xE => Ok(state.flags.O != state.flags.N || state.flags.Z),  // O != N || Z == 1
-        0xF => Err(String::from("Invalid condition")),
-        _ => Err(String::from("Invalid condition")),
+        _ => Err(Fault::InvalidCondition {
+            pc: instruction_pc(state),
+            condition: cond,
+        }),
     }
 }
 
-fn op_add(state: &mut CPU, val1: i16, val2: i16) -> i16 {
+fn op_add(state: &mut Machine, val1: i16, val2: i16) -> i16 {
     let result = ((val1 as u32) & 0xFFFF) + ((val2 as u32) & 0xFFFF);
     state.flags.C = result > u16::MAX as u32;
     // Bitwise & to handle overflow having made the 16 bits 0
@@ -815,7 +662,7 @@ fn op_add(state: &mut CPU, val1: i16, val2: i16) -> i16 {
     return (result & 0xFFFF) as i16;
 }
 
-fn op_sub(state: &mut CPU, val1: i16, val2: i16) -> i16 {
+fn op_sub(state: &mut Machine, val1: i16, val2: i16) -> i16 {
     let result = i32::from(val1) - i32::from(val2);
     state.flags.C = (val1 as u16) < (val2 as u16);
     state.flags.Z = result == 0;
@@ -825,18 +672,18 @@ fn op_sub(state: &mut CPU, val1: i16, val2: i16) -> i16 {
     return (result & 0xFFFF) as i16;
 }
 
-fn and_flags(state: &mut CPU, val1: i16, val2: i16) {
+fn and_flags(state: &mut Machine, val1: i16, val2: i16) {
     let result = val1 & val2;
     state.flags.Z = result == 0;
     state.flags.N = result < 0;
 }
 
-fn set_flags_z_n(state: &mut CPU, val: i16) {
+fn set_flags_z_n(state: &mut Machine, val: i16) {
     state.flags.Z = val == 0;
     state.flags.N = val < 0;
 }
 
-fn op_mul(state: &mut CPU, val1: i16, val2: i16) -> i16 {
+fn op_mul(state: &mut Machine, val1: i16, val2: i16) -> i16 {
     let mut result = ((val1 as u32) & 0xFFFF) * ((val2 as u32) & 0xFFFF);
     state.flags.C = result > (u16::MAX as u32);
 
@@ -848,16 +695,21 @@ fn op_mul(state: &mut CPU, val1: i16, val2: i16) -> i16 {
     return result as i16;
 }
 
-fn op_div(state: &mut CPU, val1: i16, val2: i16) -> i16 {
-    let result = val1 / val2;
-    state.flags.C = val1 % val2 != 0;
+fn op_div(state: &mut Machine, val1: i16, val2: i16) -> Result<i16, Fault> {
+    check_divisor(state, val2)?;
+    let result = val1.wrapping_div(val2);
+    // Carry on a remainder arrived in 1.0
+    if state.spec >= (1, 0) {
+        state.flags.C = val1.wrapping_rem(val2) != 0;
+    }
     state.flags.Z = result == 0;
-    state.flags.N = (result as i16) < 0;
+    state.flags.N = result < 0;
 
-    return result as i16;
+    return Ok(result);
 }
 
-fn op_mod(state: &mut CPU, val1: i16, val2: i16) -> i16 {
+fn op_mod(state: &mut Machine, val1: i16, val2: i16) -> Result<i16, Fault> {
+    check_divisor(state, val2)?;
     let v1 = i32::from(val1);
     let v2 = i32::from(val2);
 
@@ -868,20 +720,29 @@ fn op_mod(state: &mut CPU, val1: i16, val2: i16) -> i16 {
     state.flags.Z = result == 0;
     state.flags.N = result < 0;
 
-    instr_dbg_println!("Mod result: {}", result);
-    return (result & 0xFFFF) as i16;
+    return Ok((result & 0xFFFF) as i16);
 }
 
-fn op_rem(state: &mut CPU, val1: i16, val2: i16) -> i16 {
+fn op_rem(state: &mut Machine, val1: i16, val2: i16) -> Result<i16, Fault> {
+    check_divisor(state, val2)?;
     let result = i32::from(val1) % i32::from(val2);
 
     state.flags.Z = result == 0;
     state.flags.N = result < 0;
 
-    return (result & 0xFFFF) as i16;
+    return Ok((result & 0xFFFF) as i16);
+}
+
+fn check_divisor(state: &Machine, val: i16) -> Result<(), Fault> {
+    if val == 0 {
+        return Err(Fault::DivideByZero {
+            pc: instruction_pc(state),
+        });
+    }
+    return Ok(());
 }
 
-fn op_not(state: &mut CPU, val1: i16) -> i16 {
+fn op_not(state: &mut Machine, val1: i16) -> i16 {
     let result = !val1;
     state.flags.Z = result == 0;
     state.flags.N = result < 0;
@@ -889,87 +750,95 @@ fn op_not(state: &mut CPU, val1: i16) -> i16 {
     return result;
 }
 
-fn op_neg(state: &mut CPU, val1: i16) -> i16 {
-    let result = -1 * val1;
+fn op_neg(state: &mut Machine, val1: i16) -> i16 {
+    let result = val1.wrapping_neg();
     state.flags.Z = result == 0;
     state.flags.N = result < 0;
 
     return result;
 }
 
-fn push_reg(state: &mut CPU, register: usize) {
-    instr_dbg_println!(
-        "Pushing r{:X}({}) to stack",
-        register,
-        state.registers[register]
-    );
-
+fn push_reg(state: &mut Machine, register: usize) -> Result<(), Fault> {
+    check_push(state)?;
     store_mem(state, state.registers[register] as u16, state.sp);
     state.sp += 2;
+    Ok(())
 }
 
-fn pop_reg(state: &mut CPU, register: usize) {
+fn pop_reg(state: &mut Machine, register: usize) -> Result<(), Fault> {
+    check_pop(state)?;
     state.sp -= 2;
     let addr = state.sp;
     state.registers[register] = load_mem(state, addr) as i16;
-    instr_dbg_println!(
-        "Popped r{:X}({}) from stack",
-        register,
-        state.registers[register]
-    );
+    Ok(())
 }
-fn load_mem(state: &mut CPU, addr: usize) -> u16 {
-    let a = addr & 0xFFFF;
-    return (&state.mem[a..(a + 2)])
-        .read_u16::<LE>()
-        .expect("Failed to read from cpu state mem");
+
+fn call(state: &mut Machine, target: u16) -> Result<(), Fault> {
+    check_push(state)?;
+    state.stack.push(state.sp as u16);
+    store_mem(state, state.pc, state.sp);
+    state.sp += 2;
+    state.pc = target;
+    Ok(())
 }
 
-fn store_mem(state: &mut CPU, val: u16, addr: usize) {
-    let a = addr & 0xFFFF;
-    (&mut state.mem[a..(a + 2)])
-        .write_u16::<LE>(val)
-        .expect("Failed to write to mem");
+/// The values SP can take without leaving the stack.
+fn stack_range(state: &Machine) -> RangeInclusive<usize> {
+    let start = usize::from(state.stack_base);
+    return start..=(start + STACK_SIZE).min(MEM_SIZE);
+}
 
-    if (load_mem(state, a) != val) {
-        println!("Failed at writing with write_u16");
+/// Faults if pushing a word would take SP out of the stack.
+fn check_push(state: &Machine) -> Result<(), Fault> {
+    if state.sp + 2 > *stack_range(state).end() {
+        return Err(Fault::StackOverflow {
+            pc: instruction_pc(state),
+            sp: state.sp as u16,
+        });
     }
-    instr_dbg_println!(
-        "Set [{:#02X?}] to {:#02X?}, {:#02X?}",
-        addr,
-        state.mem[a],
-        state.mem[a + 1]
-    );
+    return Ok(());
 }
 
-fn load_palette(state: &mut CPU, start_addr: usize) {
-    for idx in 0..16 {
-        let addr = start_addr + idx * 3;
-        state.palette[idx] = ((state.mem[addr] as u32) << 16)
-            + ((state.mem[addr + 1] as u32) << 8)
-            + state.mem[addr + 2] as u32;
+/// Faults if popping a word would take SP out of the stack.
+fn check_pop(state: &Machine) -> Result<(), Fault> {
+    if state.sp < *stack_range(state).start() + 2 {
+        return Err(Fault::StackUnderflow {
+            pc: instruction_pc(state),
+            sp: state.sp as u16,
+        });
+    }
+    return Ok(());
+}
+fn load_mem(state: &mut Machine, addr: usize) -> u16 {
+    return state.bus.read16(addr as u16);
+}
+
+fn store_mem(state: &mut Machine, val: u16, addr: usize) {
+    state.bus.write16(addr as u16, val);
+}
+
+fn load_palette(state: &mut Machine, start_addr: usize) {
+    let mut colors = [0; 48];
+    state.bus.read(start_addr as u16, &mut colors);
+    for (idx, color) in colors.chunks(3).enumerate() {
+        state.palette[idx] = ((color[0] as u32) << 16) + ((color[1] as u32) << 8) + color[2] as u32;
     }
-    instr_dbg_println!("{:X?}", state.palette);
 }
 
 fn in_bounds(x: i16, y: i16) -> bool {
     return (x >= 0 && x < SCREEN_SIZE_X as i16) && (y >= 0 && y < SCREEN_SIZE_Y as i16);
 }
 
-fn draw_sprite(state: &mut CPU, x_coord: i16, y_coord: i16, sprite_addr: u16) {
-    //dbg_println!(
-    //    "Draw sprite from {:#02X?} at {}, {}",
-    //    sprite_addr,
-    //    x_coord,
-    //    y_coord
-    //);
-
+fn draw_sprite(state: &mut Machine, x_coord: i16, y_coord: i16, sprite_addr: u16) {
     let y_range = std::ops::Range {
         start: 0,
         end: state.graphics.spriteh,
     };
 
     let mut intersected = 0u32;
+    let sprite_size = usize::from(state.graphics.spritew) * usize::from(state.graphics.spriteh);
+    let mut sprite = vec![0; sprite_size];
+    state.bus.read(sprite_addr, &mut sprite);
 
     for y in y_range {
         // For vflip mirror which sprite address we get, height - 1 as the range does not include the end value
@@ -992,10 +861,8 @@ fn draw_sprite(state: &mut CPU, x_coord: i16, y_coord: i16, sprite_addr: u16) {
                 x
             };
 
-            let cur_sprite_addr = usize::from(y_mem) * usize::from(state.graphics.spritew)
-                + usize::from(sprite_addr)
-                + usize::from(x_mem);
-            let sprite_byte = state.mem[cur_sprite_addr];
+            let sprite_byte = sprite
+                [usize::from(y_mem) * usize::from(state.graphics.spritew) + usize::from(x_mem)];
 
             // For hflip we need to flip the pixels too, as we're mirroring across the y axis
             let (lpx, rpx) = if state.graphics.hflip {
@@ -1009,27 +876,12 @@ fn draw_sprite(state: &mut CPU, x_coord: i16, y_coord: i16, sprite_addr: u16) {
             let rx_pos = lx_pos + 1;
             let y_pos = y_coord + i16::from(y);
 
-            //dbg_println!(
-            //    "Setting {}, {}: {:#02X?} from {:#02X?}",
-            //    lx_pos,
-            //    y_pos,
-            //    lpx,
-            //    cur_sprite_addr
-            //);
-
             if lpx > 0 && in_bounds(lx_pos, y_pos) {
                 let screen_idx = y_pos as usize * SCREEN_SIZE_X as usize + lx_pos as usize;
                 intersected += state.screen[screen_idx] as u32;
                 state.screen[screen_idx] = lpx;
             }
 
-            //dbg_println!(
-            //    "Setting {}, {}: {:#02X?} from {:#02X?}",
-            //    rx_pos,
-            //    y_pos,
-            //    lpx,
-            //    cur_sprite_addr
-            //);
             if rpx > 0 && in_bounds(rx_pos, y_pos) {
                 let screen_idx = y_pos as usize * SCREEN_SIZE_X as usize + rx_pos as usize;
                 intersected += state.screen[screen_idx] as u32;
@@ -1042,52 +894,99 @@ fn draw_sprite(state: &mut CPU, x_coord: i16, y_coord: i16, sprite_addr: u16) {
 }
 
 #[derive(Debug)]
+#[allow(non_snake_case)]
 pub struct FLAGS {
-    C: bool,
-    Z: bool,
-    O: bool,
-    N: bool,
+    pub C: bool,
+    pub Z: bool,
+    pub O: bool,
+    pub N: bool,
+}
+
+impl FLAGS {
+    /// Packs the flags the way PUSHF stores them.
+    pub fn to_byte(&self) -> u8 {
+        let mut val = 0;
+        if self.C {
+            val |= 0b00000010;
+        }
+        if self.Z {
+            val |= 0b00000100;
+        }
+        if self.O {
+            val |= 0b01000000;
+        }
+        if self.N {
+            val |= 0b10000000;
+        }
+        return val;
+    }
+
+    pub fn from_byte(val: u8) -> FLAGS {
+        return FLAGS {
+            C: 0b00000010 & val > 0,
+            Z: 0b00000100 & val > 0,
+            O: 0b01000000 & val > 0,
+            N: 0b10000000 & val > 0,
+        };
+    }
 }
 
+#[derive(Debug)]
 pub struct GPU {
-    bg: u8,
-    spritew: u8,
-    spriteh: u8,
-    hflip: bool,
-    vflip: bool,
+    pub bg: u8,
+    pub spritew: u8,
+    pub spriteh: u8,
+    pub hflip: bool,
+    pub vflip: bool,
 }
 
-type Controller = u8;
+pub type Controller = u8;
+
+type Op = fn(&mut Machine, &Instruction) -> Result<(), Fault>;
 
-pub struct CPU<'a> {
-    ops: Vec<for<'b, 'c> fn(&'b mut CPU, &'c Instruction) -> Result<(), String>>,
+/// A headless Chip16 machine. Frontends drive it with `step`/`run_frame`, feed
+/// it controller state and read back the screen and palette to display.
+pub struct Machine {
+    ops: Vec<Op>,
     registers: [i16; 16],
     pc: u16,
     sp: usize,
     flags: FLAGS,
     vblnk: bool,
-    mem: [u8; 65536],
+    bus: Memory,
     graphics: GPU,
     screen: [u8; SCREEN_BUF_SIZE],
     palette: [u32; 16],
-    controls: [Controller; 2],
-    cycles: u32,
-    event_pump: &'a mut EventPump,
-    audio_state: &'a mut audio::AudioState<'a>,
+    timing: Timing,
+    audio: Box<dyn Sound>,
+    rng: ChaCha8Rng,
+    /// Where the stack starts, which moves if a program points SP elsewhere.
+    stack_base: u16,
+    /// Where the return address of each subroutine being executed is stored
+    /// on the stack, innermost last.
     stack: Vec<u16>,
-}
-
-impl CPU<'_> {
-    pub fn new<'a>(
-        mem: &'a mut [u8; 65536],
-        event_pump: &'a mut EventPump,
-        audio_state: &'a mut AudioState<'a>,
-    ) -> CPU<'a> {
-        return CPU {
+    tracer: Option<Tracer>,
+    /// The last few instructions executed and their addresses, for crash
+    /// reports.
+    history: VecDeque<(u16, Instruction)>,
+    spec: (u8, u8),
+    /// Whether opcodes newer than the spec run, with a warning, instead of
+    /// faulting.
+    allow_newer_opcodes: bool,
+    /// Opcodes newer than the spec which haven't been warned about yet.
+    unreported: [bool; 0x100],
+    warnings: Vec<String>,
+    /// How many times each opcode has been executed.
+    opcode_counts: [u64; 0x100],
+}
+
+impl Machine {
+    pub fn new(mem: &[u8; MEM_SIZE], audio: Box<dyn Sound>) -> Machine {
+        let mut machine = Machine {
             ops: vec![],
             registers: [0x00; 16],
             pc: 0x00,
-            sp: 0xFDF0,
+            sp: usize::from(STACK_START),
             flags: FLAGS {
                 N: false,
                 O: false,
@@ -1095,7 +994,7 @@ impl CPU<'_> {
                 C: false,
             },
             vblnk: false,
-            mem: mem.clone(),
+            bus: Memory::new(mem),
             graphics: GPU {
                 bg: 0x0,
                 spritew: 0,
@@ -1103,22 +1002,75 @@ impl CPU<'_> {
                 hflip: false,
                 vflip: false,
             },
-            controls: [0, 0],
             screen: [0x00; SCREEN_BUF_SIZE],
             palette: [
                 0x000000, 0x000000, 0x888888, 0xBF3232, 0xDE7AAE, 0x4C3D21, 0x905F25, 0xE49452,
                 0xEAD979, 0x537A3B, 0xABD54A, 0x252E38, 0x00467F, 0x68ABCC, 0xBCDEE4, 0xFFFFFF,
             ],
-            cycles: 0,
-            event_pump,
-            audio_state,
+            timing: Timing::default(),
+            audio,
+            rng: ChaCha8Rng::from_entropy(),
+            stack_base: STACK_START,
             stack: vec![],
+            tracer: None,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            spec: LATEST_SPEC,
+            allow_newer_opcodes: false,
+            unreported: [false; 0x100],
+            warnings: vec![],
+            opcode_counts: [0; 0x100],
         };
+        machine.init();
+        return machine;
+    }
+
+    /// Creates a machine with the ROM loaded and PC at its start address.
+    pub fn from_rom(rom: &Rom, audio: Box<dyn Sound>) -> Machine {
+        let mut machine = Machine::new(&rom.mem(), audio);
+        machine.set_pc(rom.start());
+        machine.set_spec(rom.spec_version().unwrap_or(LATEST_SPEC));
+        return machine;
+    }
+
+    pub fn spec(&self) -> (u8, u8) {
+        return self.spec;
     }
-    pub fn init(&mut self) {
-        for _ in 0..0xFF {
-            self.ops.push(error);
+
+    /// Runs the machine as the given revision of the spec. Instructions added
+    /// by later revisions are invalid opcodes, unless newer opcodes are
+    /// allowed.
+    pub fn set_spec(&mut self, spec: (u8, u8)) {
+        self.spec = spec;
+        self.init();
+        for opcode in 0..=0xFF {
+            let newer = opcode_spec(opcode) > spec;
+            self.unreported[usize::from(opcode)] = newer && self.allow_newer_opcodes;
+            if newer && !self.allow_newer_opcodes {
+                self.ops[usize::from(opcode)] = error;
+            }
         }
+    }
+
+    /// Lets instructions newer than the spec run, as plenty of ROMs declare
+    /// an older spec than they use. Each is warned about the first time it
+    /// runs.
+    pub fn set_allow_newer_opcodes(&mut self, allow: bool) {
+        self.allow_newer_opcodes = allow;
+        self.set_spec(self.spec);
+    }
+
+    pub fn allow_newer_opcodes(&self) -> bool {
+        return self.allow_newer_opcodes;
+    }
+
+    /// Takes the warnings raised since the last call, such as instructions
+    /// newer than the spec.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        return std::mem::take(&mut self.warnings);
+    }
+
+    fn init(&mut self) {
+        self.ops = vec![error; 0x100];
         self.ops[0x00] = nop;
         self.ops[0x01] = cls;
         self.ops[0x02] = vblnk;
@@ -1217,140 +1169,397 @@ impl CPU<'_> {
         self.ops[0xE5] = neg_rx_ry;
     }
 
-    fn execute(&mut self, instruction: &Instruction) {
-        let op = self.ops[usize::from(instruction[0])];
-        op(self, instruction).expect("Failed to run instruction");
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), Fault> {
+        let opcode = instruction[0];
+        self.opcode_counts[usize::from(opcode)] += 1;
+        if self.unreported[usize::from(opcode)] {
+            self.unreported[usize::from(opcode)] = false;
+            let (major, minor) = opcode_spec(opcode);
+            self.warnings.push(format!(
+                "{:#06X}: {} is a spec {}.{} instruction, newer than {}.{}",
+                self.pc.wrapping_sub(4),
+                disassemble(instruction, 0),
+                major,
+                minor,
+                self.spec.0,
+                self.spec.1
+            ));
+        }
+        let op = self.ops[usize::from(opcode)];
+        return op(self, instruction);
     }
 
-    pub fn set_pc(&mut self, address: [u8; 2]) {
-        self.pc = (address[1] as u16) << 8 + address[0] as u16;
+    pub fn set_pc(&mut self, address: u16) {
+        self.pc = address;
     }
 
-    pub fn run(&mut self, renderer: &mut Renderer) -> Result<(), String> {
-        self.stack.push(self.pc);
-
-        let mut previous_frame_time = Instant::now();
-        'running: loop {
-            let pc = usize::from(self.pc);
-            let next_inst = [
-                self.mem[pc],
-                self.mem[pc + 1],
-                self.mem[pc + 2],
-                self.mem[pc + 3],
-            ];
-
-            if (next_inst[0] != 0x10
-                || (((next_inst[3] as u16) << 8) | next_inst[2] as u16) != self.pc)
-                && next_inst[0] != 0x2
-            {
-                instr_dbg_println!(
-                    "{:X}: {:X} {:X} {:X} {:X}",
-                    self.pc,
-                    next_inst[0],
-                    next_inst[1],
-                    next_inst[2],
-                    next_inst[3]
-                );
-                instr_dbg_println!("{:X?}", self.stack);
-            }
+    pub fn timing(&self) -> &Timing {
+        return &self.timing;
+    }
 
-            self.pc += 4;
-            if let Some(cur_stack) = self.stack.last_mut() {
-                *cur_stack = self.pc;
-            }
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.timing.set_clock(clock);
+    }
 
-            self.execute(&next_inst);
-
-            self.cycles += 1;
-
-            //dbg_println!("{}", cycles);
-            if self.cycles >= FRAME_CYCLES {
-                self.cycles = 0;
-                //dbg_println!("Sleeping and drawing");
-                renderer.draw(self)?;
-                //self.audio.play((1000 / 60) * 2, self.audio_file)?;
-
-                if self.audio_state.is_finished() {
-                    self.audio_state.clear();
-                    //println!("Finished playing sound");
-                }
-                let passed_duration = previous_frame_time.elapsed();
-                //println!(
-                //    "Time elapsed since last frame draw is: {:?}, frame duration: {:?}",
-                //    passed_duration, FRAME_DURATION
-                //);
-                if passed_duration < FRAME_DURATION {
-                    let sleep_duration = FRAME_DURATION - passed_duration;
-                    //dbg_println!("Sleeping for {:?}", sleep_duration);
-
-                    thread::sleep(sleep_duration);
-                }
-                self.vblnk = true;
-
-                let mut up_events: Vec<Keycode> = vec![];
-                for event in self.event_pump.poll_iter() {
-                    match event {
-                        Event::Quit { .. } => break 'running,
-                        Event::KeyDown {
-                            keycode: Some(keycode),
-                            ..
-                        } => match keycode {
-                            Keycode::W => self.controls[0] |= 0b00000001,
-                            Keycode::S => self.controls[0] |= 0b00000010,
-                            Keycode::A => self.controls[0] |= 0b00000100,
-                            Keycode::D => self.controls[0] |= 0b00001000,
-                            Keycode::G => self.controls[0] |= 0b00010000,
-                            Keycode::H => self.controls[0] |= 0b00100000,
-                            Keycode::J => self.controls[0] |= 0b01000000,
-                            Keycode::K => self.controls[0] |= 0b10000000,
-                            _ => {}
-                        },
-                        Event::KeyUp {
-                            keycode: Some(keycode),
-                            ..
-                        } => up_events.push(keycode),
-                        _ => {}
-                    }
-                }
-
-                self.update_control_mem();
-
-                for keycode in up_events {
-                    match keycode {
-                        Keycode::W => self.controls[0] &= 0b11111110,
-                        Keycode::S => self.controls[0] &= 0b11111101,
-                        Keycode::A => self.controls[0] &= 0b11111011,
-                        Keycode::D => self.controls[0] &= 0b11110111,
-                        Keycode::G => self.controls[0] &= 0b11101111,
-                        Keycode::H => self.controls[0] &= 0b11011111,
-                        Keycode::J => self.controls[0] &= 0b10111111,
-                        Keycode::K => self.controls[0] &= 0b01111111,
-                        _ => {}
-                    }
-                }
-
-                previous_frame_time += FRAME_DURATION;
-            } else {
-                self.vblnk = false;
+    /// Tells the sound generator how fast emulation is running, None when
+    /// paused or uncapped.
+    pub fn set_sound_speed(&mut self, speed: Option<f64>) {
+        self.audio.set_speed(speed);
+    }
+
+    /// Reseeds the generator used by RND so runs can be reproduced.
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Executes a single instruction, returning true if it completed a frame.
+    ///
+    /// An instruction that faults is skipped over and still takes its cycles,
+    /// so the machine can carry on after one if the frontend chooses to. The
+    /// fault comes with whether it completed a frame all the same.
+    pub fn step(&mut self) -> Result<bool, (Fault, bool)> {
+        let next_inst = self.bus.fetch(self.pc);
+
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.trace(self);
+            self.tracer = Some(tracer);
+        }
+
+        self.bus.clear_accesses();
+
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.pc, next_inst));
+
+        self.pc = self.pc.wrapping_add(4);
+
+        let result = self.execute(&next_inst);
+
+        // Forget calls whose return address has been popped, whether by RET or
+        // by a program adjusting SP itself
+        while self
+            .stack
+            .last()
+            .is_some_and(|addr| usize::from(*addr) >= self.sp)
+        {
+            self.stack.pop();
+        }
+
+        let frame_done = if self.vblnk {
+            self.timing.finish_frame();
+            true
+        } else {
+            self.timing.tick(next_inst[0])
+        };
+        self.vblnk = false;
+
+        if frame_done {
+            if let Some(tracer) = &mut self.tracer {
+                tracer.end_frame();
+            }
+            self.audio.frame();
+            if self.audio.is_finished() {
+                self.audio.clear();
             }
         }
-        Ok(())
+        result.map_err(|fault| (fault, frame_done))?;
+        return Ok(frame_done);
+    }
+
+    /// Runs until the end of the current frame, stopping early at a fault.
+    /// The fault comes with whether its instruction ended the frame anyway.
+    pub fn run_frame(&mut self) -> Result<(), (Fault, bool)> {
+        while !self.step()? {}
+        return Ok(());
+    }
+
+    /// The subroutines being executed, innermost first, as the stack address
+    /// each one's return address is stored at and the address itself.
+    pub fn call_stack(&self) -> Vec<(u16, u16)> {
+        return self
+            .stack
+            .iter()
+            .rev()
+            .map(|addr| {
+                let bytes = [self.peek(*addr), self.peek(addr.wrapping_add(1))];
+                (*addr, u16::from_le_bytes(bytes))
+            })
+            .collect();
+    }
+
+    /// The last instructions executed, oldest first, with their addresses.
+    pub fn history(&self) -> &VecDeque<(u16, [u8; 4])> {
+        return &self.history;
+    }
+
+    /// The instruction at PC, which `step` will execute next.
+    pub fn next_instruction(&self) -> [u8; 4] {
+        return self.bus.fetch(self.pc);
+    }
+
+    /// Sets the tracer to log executed instructions to, returning the
+    /// previous one.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        return std::mem::replace(&mut self.tracer, tracer);
+    }
+
+    /// Enables recording the memory accesses made by each instruction.
+    pub fn set_record_accesses(&mut self, record: bool) {
+        self.bus.set_record_accesses(record);
+    }
+
+    /// Memory accesses made by the last executed instruction.
+    pub fn accesses(&self) -> &[MemAccess] {
+        return self.bus.accesses();
+    }
+
+    /// Starts counting accesses to each address from zero, or stops.
+    pub fn set_count_accesses(&mut self, count: bool) {
+        self.bus.set_count_accesses(count);
+    }
+
+    /// Reads and writes of each address since counting started.
+    pub fn access_counts(&self) -> Option<&[AccessCount]> {
+        return self.bus.access_counts();
+    }
+
+    /// How many times each opcode has been executed, including ones that
+    /// faulted.
+    pub fn opcode_counts(&self) -> &[u64; 0x100] {
+        return &self.opcode_counts;
+    }
+
+    pub fn registers(&self) -> &[i16; 16] {
+        return &self.registers;
+    }
+
+    pub fn pc(&self) -> u16 {
+        return self.pc;
+    }
+
+    pub fn sp(&self) -> usize {
+        return self.sp;
     }
 
-    pub fn screen(&mut self) -> [u8; SCREEN_BUF_SIZE] {
-        return self.screen;
+    pub fn flags(&self) -> &FLAGS {
+        return &self.flags;
     }
 
-    pub fn palette(&mut self) -> [u32; 16] {
+    /// The RAM, without the pad ports mapped over it.
+    pub fn mem(&self) -> &[u8; MEM_SIZE] {
+        return self.bus.ram();
+    }
+
+    /// Reads a byte as a program would, without it counting as an access.
+    pub fn peek(&self, addr: u16) -> u8 {
+        return self.bus.peek(addr);
+    }
+
+    pub fn graphics(&self) -> &GPU {
+        return &self.graphics;
+    }
+
+    pub fn screen(&self) -> &[u8; SCREEN_BUF_SIZE] {
+        return &self.screen;
+    }
+
+    pub fn palette(&self) -> [u32; 16] {
         return self.palette;
     }
 
-    pub fn bgc(&mut self) -> u8 {
+    pub fn bgc(&self) -> u8 {
         return self.graphics.bg;
     }
 
-    fn update_control_mem(&mut self) {
-        self.mem[0xFFF0] = self.controls[0];
-        self.mem[0xFFF2] = self.controls[1];
+    pub fn controllers(&self) -> [Controller; 2] {
+        return self.bus.controllers();
+    }
+
+    /// Captures the machine, and the sound it's playing, for a save state.
+    pub fn state(&mut self) -> State {
+        return State {
+            registers: self.registers,
+            pc: self.pc,
+            sp: self.sp as u16,
+            flags: self.flags.to_byte(),
+            vblnk: self.vblnk,
+            spec: self.spec,
+            allow_newer_opcodes: self.allow_newer_opcodes,
+            cycles: self.timing.cycles(),
+            bg: self.graphics.bg,
+            spritew: self.graphics.spritew,
+            spriteh: self.graphics.spriteh,
+            hflip: self.graphics.hflip,
+            vflip: self.graphics.vflip,
+            palette: self.palette,
+            controls: self.bus.controllers(),
+            rng_seed: self.rng.get_seed(),
+            rng_stream: self.rng.get_stream(),
+            rng_word_pos: self.rng.get_word_pos(),
+            stack_base: self.stack_base,
+            stack: self.stack.clone(),
+            sound: self.audio.state(),
+            mem: Box::new(*self.bus.ram()),
+            screen: Box::new(self.screen),
+        };
+    }
+
+    pub fn restore(&mut self, state: &State) {
+        self.registers = state.registers;
+        self.pc = state.pc;
+        self.sp = state.sp as usize;
+        self.flags = FLAGS::from_byte(state.flags);
+        self.vblnk = state.vblnk;
+        // Rebuilding the opcode table would repeat newer opcode warnings
+        if (state.spec, state.allow_newer_opcodes) != (self.spec, self.allow_newer_opcodes) {
+            self.allow_newer_opcodes = state.allow_newer_opcodes;
+            self.set_spec(state.spec);
+        }
+        self.timing.set_cycles(state.cycles);
+        self.graphics = GPU {
+            bg: state.bg,
+            spritew: state.spritew,
+            spriteh: state.spriteh,
+            hflip: state.hflip,
+            vflip: state.vflip,
+        };
+        self.palette = state.palette;
+        for (pad, controls) in state.controls.into_iter().enumerate() {
+            self.bus.set_controller(pad, controls);
+        }
+        self.rng = ChaCha8Rng::from_seed(state.rng_seed);
+        self.rng.set_stream(state.rng_stream);
+        self.rng.set_word_pos(state.rng_word_pos);
+        self.stack_base = state.stack_base;
+        self.stack = state.stack.clone();
+        self.history.clear();
+        self.audio.restore(&state.sound);
+        self.bus.set_ram(&state.mem);
+        self.screen = *state.screen;
+        self.bus.clear_accesses();
+    }
+
+    /// Sets the button state of a pad, which programs read from its I/O port.
+    pub fn set_controller(&mut self, pad: usize, state: Controller) {
+        self.bus.set_controller(pad, state);
+    }
+}
+
+#[cfg(test)]
+impl Machine {
+    /// A silent machine with `program` loaded at 0x0000, for tests.
+    pub(crate) fn with_program(program: &[u8]) -> Machine {
+        let mut mem = [0; MEM_SIZE];
+        mem[..program.len()].copy_from_slice(program);
+        return Machine::new(&mem, Box::new(crate::sound::NullSound));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::NullSound;
+
+    // LDI r0, 0x0001 at 0x0000 and LDI r0, 0x1234 at 0x0244
+    fn program() -> Vec<u8> {
+        let mut program = vec![0; 0x0248];
+        program[0x0000..0x0004].copy_from_slice(&[0x20, 0x00, 0x01, 0x00]);
+        program[0x0244..0x0248].copy_from_slice(&[0x20, 0x00, 0x34, 0x12]);
+        return program;
+    }
+
+    #[test]
+    fn starts_at_zero_without_header() {
+        let rom = Rom::from_bytes(program(), true).unwrap();
+        let mut machine = Machine::from_rom(&rom, Box::new(NullSound));
+        assert_eq!(machine.pc(), 0x0000);
+
+        machine.step().unwrap();
+        assert_eq!(machine.registers()[0], 0x0001);
+        assert_eq!(machine.pc(), 0x0004);
+    }
+
+    #[test]
+    fn starts_at_header_start_address() {
+        let rom =
+            Rom::from_bytes(Rom::with_header((1, 1), 0x0000, program()).to_bytes(), true).unwrap();
+        let machine = Machine::from_rom(&rom, Box::new(NullSound));
+        assert_eq!(machine.pc(), 0x0000);
+
+        let rom =
+            Rom::from_bytes(Rom::with_header((1, 1), 0x0244, program()).to_bytes(), true).unwrap();
+        let mut machine = Machine::from_rom(&rom, Box::new(NullSound));
+        assert_eq!(machine.pc(), 0x0244);
+
+        machine.step().unwrap();
+        assert_eq!(machine.registers()[0], 0x1234);
+        assert_eq!(machine.spec(), (1, 1));
+        assert_eq!(machine.pc(), 0x0248);
+    }
+
+    #[test]
+    fn follows_the_spec_revision() {
+        // LDI r0, 7; DIVI r0, 2; NOTI r1, 0; NOTI r1, 0
+        let program = [
+            0x20, 0x00, 0x07, 0x00, 0xA0, 0x00, 0x02, 0x00, 0xE0, 0x01, 0x00, 0x00, 0xE0, 0x01,
+            0x00, 0x00,
+        ];
+        let mut machine = Machine::with_program(&program);
+        machine.set_spec((1, 1));
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(
+            machine.step(),
+            Err((
+                Fault::InvalidOpcode {
+                    pc: 0x0008,
+                    op: 0xE0
+                },
+                false
+            ))
+        );
+
+        let mut machine = Machine::with_program(&program);
+        machine.set_spec((1, 1));
+        machine.set_allow_newer_opcodes(true);
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        assert!(machine.flags().C);
+        assert_eq!(machine.registers()[1], -1);
+        assert_eq!(
+            machine.take_warnings(),
+            ["0x0008: noti r1, 0x0000 is a spec 1.3 instruction, newer than 1.1"]
+        );
+        assert!(machine.take_warnings().is_empty());
+
+        let mut machine = Machine::with_program(&program);
+        machine.set_spec((0, 7));
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert!(!machine.flags().C);
+    }
+
+    #[test]
+    fn shifts_by_the_low_bits_of_a_register() {
+        // LDI r0, 0x8421; LDI r1, 16; SHL r0, r1; LDI r1, -1; SHR r0, r1; SAR r0, r1
+        let mut machine = Machine::with_program(&[
+            0x20, 0x00, 0x21, 0x84, 0x20, 0x01, 0x10, 0x00, 0xB3, 0x10, 0x00, 0x00, 0x20, 0x01,
+            0xFF, 0xFF, 0xB4, 0x10, 0x00, 0x00, 0xB5, 0x10, 0x00, 0x00,
+        ]);
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers()[0], 0x8421u16 as i16);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.registers()[0], 0x0001);
+
+        machine.set_pc(0x0000);
+        machine.step().unwrap();
+        machine.set_pc(0x0014);
+        machine.step().unwrap();
+        assert_eq!(machine.registers()[0], -1);
     }
 }
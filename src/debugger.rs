@@ -0,0 +1,646 @@
+use std::fmt::Write;
+
+use crate::cpu::MEM_SIZE;
+use crate::disasm::disassemble;
+use crate::fault::crash_report;
+use crate::symbols::Symbols;
+use crate::Machine;
+
+const CALL_HHLL: u8 = 0x14;
+const RET: u8 = 0x15;
+const CX_HHLL: u8 = 0x17;
+const CALL_RX: u8 = 0x18;
+
+const HELP: &str = "\
+c, continue          resume execution
+s, step [n]          execute n instructions (default 1)
+n, next              step over CALL/Cx
+finish               run until the current subroutine returns
+b, break <addr>      break when PC reaches addr
+bo <opcode>          break before executing opcode
+w, watch <start>[-<end>] [r|w|rw]
+                     break on memory access in start..=end (default rw)
+i, info              list breakpoints
+d, delete <n>        delete breakpoint n
+r, regs              show registers and flags
+bt, backtrace        show the call stack
+x <addr> [len]       dump memory (default 16 bytes)
+count on|off         start or stop counting memory accesses
+count [n]            show the n most accessed addresses (default 10)
+q, quit              exit the emulator";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint {
+    Pc(u16),
+    Opcode(u8),
+    Memory {
+        start: u16,
+        end: u16,
+        access: Access,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepMode {
+    Run,
+    Step(u32),
+    /// Run until PC returns to `pc` with the stack back at `sp`.
+    Over {
+        pc: u16,
+        sp: usize,
+    },
+    /// Run until a RET pops the stack below `sp`.
+    Out {
+        sp: usize,
+    },
+}
+
+/// Interactive debugger that drives a `Machine` one instruction at a time.
+/// Frontends feed it command lines and print what it returns.
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    paused: bool,
+    mode: StepMode,
+    // Set when resuming so we don't immediately re-break at the current PC
+    resuming: bool,
+    counting: bool,
+    quit: bool,
+    // Whether the last call to run_frame reached the end of a frame
+    finished_frame: bool,
+    symbols: Symbols,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        return Debugger::new();
+    }
+}
+
+impl Debugger {
+    /// Creates a debugger that starts paused before the first instruction.
+    pub fn new() -> Debugger {
+        return Debugger {
+            breakpoints: vec![],
+            paused: true,
+            mode: StepMode::Run,
+            resuming: false,
+            counting: false,
+            quit: false,
+            finished_frame: false,
+            symbols: Symbols::default(),
+        };
+    }
+
+    pub fn is_paused(&self) -> bool {
+        return self.paused;
+    }
+
+    pub fn should_quit(&self) -> bool {
+        return self.quit;
+    }
+
+    /// Whether the last `run_frame` reached the end of a frame, even if it
+    /// broke on the instruction that ended it.
+    pub fn finished_frame(&self) -> bool {
+        return self.finished_frame;
+    }
+
+    /// Sets the names shown for addresses in the call stack.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        return &self.breakpoints;
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    /// Runs the machine until the end of the frame or until execution
+    /// breaks. Returns a message describing why it stopped, if it did.
+    pub fn run_frame(&mut self, machine: &mut Machine) -> Option<String> {
+        machine.set_record_accesses(self.has_watchpoints());
+        machine.set_count_accesses(self.counting);
+        self.finished_frame = false;
+
+        while !self.paused {
+            if !self.resuming {
+                if let Some(reason) = self.check_before(machine) {
+                    return Some(self.pause(machine, &reason));
+                }
+            }
+            self.resuming = false;
+
+            let opcode = machine.next_instruction()[0];
+            let frame_done = match machine.step() {
+                Ok(frame_done) => frame_done,
+                Err((fault, frame_done)) => {
+                    self.finished_frame = frame_done;
+                    let report = crash_report(machine, &fault, &self.symbols);
+                    return Some(self.pause(machine, &report));
+                }
+            };
+            self.finished_frame = frame_done;
+
+            if let Some(reason) = self.check_after(machine, opcode) {
+                return Some(self.pause(machine, &reason));
+            }
+            if frame_done {
+                return None;
+            }
+        }
+        return None;
+    }
+
+    /// Executes a debugger command, returning the output to show the user.
+    pub fn execute(&mut self, line: &str, machine: &Machine) -> String {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return String::new(),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let result = match command {
+            "c" | "continue" => {
+                self.resume(StepMode::Run);
+                Ok(String::new())
+            }
+            "s" | "step" => args
+                .first()
+                .map_or(Ok(1), |count| parse_number(count))
+                .map(|count| {
+                    self.resume(StepMode::Step(count.max(1)));
+                    String::new()
+                }),
+            "n" | "next" => {
+                let opcode = machine.next_instruction()[0];
+                if opcode == CALL_HHLL || opcode == CX_HHLL || opcode == CALL_RX {
+                    self.resume(StepMode::Over {
+                        pc: machine.pc().wrapping_add(4),
+                        sp: machine.sp(),
+                    });
+                } else {
+                    self.resume(StepMode::Step(1));
+                }
+                Ok(String::new())
+            }
+            "finish" => {
+                self.resume(StepMode::Out { sp: machine.sp() });
+                Ok(String::new())
+            }
+            "b" | "break" => parse_arg(&args, 0).and_then(address).map(|addr| {
+                self.breakpoints.push(Breakpoint::Pc(addr));
+                format!("Breakpoint {} at {:#06X}", self.breakpoints.len() - 1, addr)
+            }),
+            "bo" => parse_arg(&args, 0).and_then(|opcode| {
+                let opcode = u8::try_from(opcode)
+                    .map_err(|_| format!("Opcode {:#X} out of range", opcode))?;
+                self.breakpoints.push(Breakpoint::Opcode(opcode));
+                Ok(format!(
+                    "Breakpoint {} on opcode {:#04X}",
+                    self.breakpoints.len() - 1,
+                    opcode
+                ))
+            }),
+            "w" | "watch" => self.watch(&args),
+            "i" | "info" => Ok(self.info()),
+            "d" | "delete" => parse_arg(&args, 0).and_then(|index| {
+                if (index as usize) < self.breakpoints.len() {
+                    self.breakpoints.remove(index as usize);
+                    Ok(format!("Deleted breakpoint {}", index))
+                } else {
+                    Err(format!("No breakpoint {}", index))
+                }
+            }),
+            "r" | "regs" => Ok(registers(machine)),
+            "bt" | "backtrace" => Ok(call_stack(machine, machine.pc(), &self.symbols)),
+            "x" => parse_arg(&args, 0).and_then(|addr| {
+                if addr as usize >= MEM_SIZE {
+                    return Err(format!("{:#X} is past the end of memory", addr));
+                }
+                let len = args.get(1).map_or(Ok(16), |len| parse_number(len))?;
+                // Stop at the end of memory rather than wrapping around
+                let len = len.min(MEM_SIZE as u32 - addr);
+                Ok(dump_memory(machine, addr as u16, len))
+            }),
+            "count" => match args.first().copied() {
+                Some("on") => {
+                    self.counting = true;
+                    Ok(String::from("Counting memory accesses"))
+                }
+                Some("off") => {
+                    self.counting = false;
+                    Ok(String::from("Stopped counting memory accesses"))
+                }
+                count => count
+                    .map_or(Ok(10), parse_number)
+                    .map(|count| access_counts(machine, count as usize)),
+            },
+            "q" | "quit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            "h" | "help" => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command '{}', try 'help'", command)),
+        };
+
+        return result.unwrap_or_else(|e| e);
+    }
+
+    fn resume(&mut self, mode: StepMode) {
+        self.mode = mode;
+        self.paused = false;
+        self.resuming = true;
+    }
+
+    fn pause(&mut self, machine: &Machine, reason: &str) -> String {
+        self.paused = true;
+        self.mode = StepMode::Run;
+        if reason.is_empty() {
+            return current_instruction(machine);
+        }
+        return format!("{}\n{}", reason, current_instruction(machine));
+    }
+
+    fn has_watchpoints(&self) -> bool {
+        return self
+            .breakpoints
+            .iter()
+            .any(|breakpoint| matches!(breakpoint, Breakpoint::Memory { .. }));
+    }
+
+    fn check_before(&self, machine: &Machine) -> Option<String> {
+        let pc = machine.pc();
+        let opcode = machine.next_instruction()[0];
+
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            match breakpoint {
+                Breakpoint::Pc(addr) if *addr == pc => {
+                    return Some(format!("Breakpoint {} at {:#06X}", index, pc));
+                }
+                Breakpoint::Opcode(op) if *op == opcode => {
+                    return Some(format!("Breakpoint {} on opcode {:#04X}", index, opcode));
+                }
+                _ => {}
+            }
+        }
+
+        if let StepMode::Over { pc: return_pc, sp } = self.mode {
+            if pc == return_pc && machine.sp() == sp {
+                return Some(String::new());
+            }
+        }
+        return None;
+    }
+
+    fn check_after(&mut self, machine: &Machine, opcode: u8) -> Option<String> {
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            if let Breakpoint::Memory { start, end, access } = breakpoint {
+                let hit = machine.accesses().iter().find(|mem| {
+                    mem.overlaps(*start, *end)
+                        && match access {
+                            Access::Read => !mem.write,
+                            Access::Write => mem.write,
+                            Access::ReadWrite => true,
+                        }
+                });
+                if let Some(mem) = hit {
+                    return Some(format!(
+                        "Watchpoint {}: {} {:#06X} ({} bytes)",
+                        index,
+                        if mem.write { "write to" } else { "read from" },
+                        mem.addr,
+                        mem.len
+                    ));
+                }
+            }
+        }
+
+        match self.mode {
+            StepMode::Step(1) => return Some(String::new()),
+            StepMode::Step(count) => self.mode = StepMode::Step(count - 1),
+            StepMode::Out { sp } if opcode == RET && machine.sp() < sp => {
+                return Some(String::new());
+            }
+            _ => {}
+        }
+        return None;
+    }
+
+    fn watch(&mut self, args: &[&str]) -> Result<String, String> {
+        let range = args.first().ok_or("Missing address")?;
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (address(parse_number(start)?)?, address(parse_number(end)?)?),
+            None => {
+                let addr = address(parse_number(range)?)?;
+                (addr, addr)
+            }
+        };
+        if start > end {
+            return Err(format!("Range {:#06X}-{:#06X} is backwards", start, end));
+        }
+        let access = match args.get(1).copied() {
+            Some("r") => Access::Read,
+            Some("w") => Access::Write,
+            Some("rw") | None => Access::ReadWrite,
+            Some(other) => return Err(format!("Unknown access '{}'", other)),
+        };
+
+        self.breakpoints
+            .push(Breakpoint::Memory { start, end, access });
+        return Ok(format!(
+            "Watchpoint {} on {:#06X}-{:#06X}",
+            self.breakpoints.len() - 1,
+            start,
+            end
+        ));
+    }
+
+    fn info(&self) -> String {
+        let mut out = String::new();
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            let _ = match breakpoint {
+                Breakpoint::Pc(addr) => writeln!(out, "{}: pc {:#06X}", index, addr),
+                Breakpoint::Opcode(op) => writeln!(out, "{}: opcode {:#04X}", index, op),
+                Breakpoint::Memory { start, end, access } => writeln!(
+                    out,
+                    "{}: memory {:#06X}-{:#06X} {:?}",
+                    index, start, end, access
+                ),
+            };
+        }
+        if out.is_empty() {
+            out.push_str("No breakpoints");
+        }
+        return out.trim_end().to_string();
+    }
+}
+
+/// Parses a decimal number, or hex with a 0x or $ prefix.
+fn parse_number(text: &str) -> Result<u32, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+    return parsed.map_err(|_| format!("Invalid number '{}'", text));
+}
+
+fn parse_arg(args: &[&str], index: usize) -> Result<u32, String> {
+    return parse_number(args.get(index).ok_or("Missing argument")?);
+}
+
+fn address(addr: u32) -> Result<u16, String> {
+    return u16::try_from(addr).map_err(|_| format!("Address {:#X} out of range", addr));
+}
+
+fn current_instruction(machine: &Machine) -> String {
+    let instruction = disassemble(machine.mem(), machine.pc());
+    return format!(
+        "{:04X}: {:02X} {:02X} {:02X} {:02X}  {}",
+        instruction.addr,
+        instruction.bytes[0],
+        instruction.bytes[1],
+        instruction.bytes[2],
+        instruction.bytes[3],
+        instruction
+    );
+}
+
+/// Lists the subroutines being executed, innermost first, starting with the
+/// one `pc` is in and then each return address on the stack.
+pub fn call_stack(machine: &Machine, pc: u16, symbols: &Symbols) -> String {
+    let describe = |addr: u16| match symbols.describe(addr) {
+        Some(name) => format!("{:04X} {}", addr, name),
+        None => format!("{:04X}", addr),
+    };
+    let mut out = format!("#0 {}", describe(pc));
+    for (index, (stored_at, addr)) in machine.call_stack().into_iter().enumerate() {
+        let _ = write!(
+            out,
+            "\n#{} {}  (from {:04X})",
+            index + 1,
+            describe(addr),
+            stored_at
+        );
+    }
+    return out;
+}
+
+pub(crate) fn registers(machine: &Machine) -> String {
+    let mut out = String::new();
+    for (index, value) in machine.registers().iter().enumerate() {
+        let _ = write!(out, "r{:X}={:04X}", index, *value as u16);
+        out.push(if index % 8 == 7 { '\n' } else { ' ' });
+    }
+    let flags = machine.flags();
+    let _ = write!(
+        out,
+        "pc={:04X} sp={:04X} C={} Z={} O={} N={}",
+        machine.pc(),
+        machine.sp(),
+        flags.C as u8,
+        flags.Z as u8,
+        flags.O as u8,
+        flags.N as u8
+    );
+    return out;
+}
+
+fn dump_memory(machine: &Machine, addr: u16, len: u32) -> String {
+    let mut out = String::new();
+    for row in (0..len).step_by(16) {
+        let _ = write!(out, "{:04X}:", addr as u32 + row);
+        for offset in row..(row + 16).min(len) {
+            let _ = write!(out, " {:02X}", machine.peek((addr as u32 + offset) as u16));
+        }
+        out.push('\n');
+    }
+    return out.trim_end().to_string();
+}
+
+fn access_counts(machine: &Machine, count: usize) -> String {
+    let Some(counts) = machine.access_counts() else {
+        return String::from("Not counting accesses, start with 'count on'");
+    };
+    let mut addrs: Vec<usize> = (0..counts.len())
+        .filter(|addr| counts[*addr].reads + counts[*addr].writes > 0)
+        .collect();
+    addrs.sort_by_key(|addr| std::cmp::Reverse(counts[*addr].reads + counts[*addr].writes));
+
+    let mut out = String::new();
+    for addr in addrs.into_iter().take(count) {
+        let _ = writeln!(
+            out,
+            "{:#06X}: {} reads, {} writes",
+            addr, counts[addr].reads, counts[addr].writes
+        );
+    }
+    if out.is_empty() {
+        out.push_str("No accesses counted");
+    }
+    return out.trim_end().to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::Clock;
+
+    // 0000: CALL 0x0010
+    // 0004: LDI r1, 2
+    // 0008: JMP 0x0008
+    // 0010: STM r0, 0x0100
+    // 0014: RET
+    const PROGRAM: [u8; 24] = [
+        0x14, 0x00, 0x10, 0x00, 0x20, 0x01, 0x02, 0x00, 0x10, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x30, 0x00, 0x00, 0x01, 0x15, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn steps_and_breaks_on_pc() {
+        let mut machine = Machine::with_program(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        debugger.execute("s", &machine);
+        debugger.run_frame(&mut machine);
+        assert!(debugger.is_paused());
+        assert_eq!(machine.pc(), 0x0010);
+        assert_eq!(
+            debugger.execute("bt", &machine),
+            "#0 0010\n#1 0004  (from FDF0)"
+        );
+
+        debugger.execute("b 0x0004", &machine);
+        debugger.execute("c", &machine);
+        let reason = debugger.run_frame(&mut machine).unwrap();
+        assert!(reason.starts_with("Breakpoint 0 at 0x0004"));
+        assert_eq!(machine.pc(), 0x0004);
+    }
+
+    #[test]
+    fn steps_over_and_out_of_calls() {
+        let mut machine = Machine::with_program(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        debugger.execute("n", &machine);
+        debugger.run_frame(&mut machine);
+        assert_eq!(machine.pc(), 0x0004);
+
+        let mut machine = Machine::with_program(&PROGRAM);
+        debugger.execute("s", &machine);
+        debugger.run_frame(&mut machine);
+        debugger.execute("finish", &machine);
+        debugger.run_frame(&mut machine);
+        assert!(debugger.is_paused());
+        assert_eq!(machine.pc(), 0x0004);
+    }
+
+    #[test]
+    fn breaks_on_watchpoints_and_opcodes() {
+        let mut machine = Machine::with_program(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        debugger.execute("w 0x0101 r", &machine);
+        debugger.execute("w 0x0101", &machine);
+        debugger.execute("c", &machine);
+        let reason = debugger.run_frame(&mut machine).unwrap();
+        assert!(reason.starts_with("Watchpoint 1: write to 0x0100"));
+        assert_eq!(machine.pc(), 0x0014);
+
+        debugger.execute("d 1", &machine);
+        debugger.execute("bo 0x10", &machine);
+        debugger.execute("c", &machine);
+        let reason = debugger.run_frame(&mut machine).unwrap();
+        assert!(reason.starts_with("Breakpoint 1 on opcode 0x10"));
+        assert_eq!(machine.pc(), 0x0008);
+    }
+
+    #[test]
+    fn finishes_frames_that_break_on_their_last_instruction() {
+        // DIV r0, r1 at a clock where every instruction ends a frame
+        let mut machine = Machine::with_program(&[0xA1, 0x10, 0x00, 0x00]);
+        machine.set_clock(Clock::Hz(60));
+        let mut debugger = Debugger::new();
+
+        debugger.execute("c", &machine);
+        let reason = debugger.run_frame(&mut machine).unwrap();
+        assert!(reason.starts_with("Division by zero at 0x0000"));
+        assert!(debugger.is_paused());
+        assert!(debugger.finished_frame());
+
+        debugger.run_frame(&mut machine);
+        assert!(!debugger.finished_frame());
+    }
+
+    #[test]
+    fn rejects_out_of_range_breakpoints() {
+        let machine = Machine::with_program(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute("b 0x10004", &machine),
+            "Address 0x10004 out of range"
+        );
+        assert_eq!(
+            debugger.execute("bo 0x1A0", &machine),
+            "Opcode 0x1A0 out of range"
+        );
+        assert_eq!(
+            debugger.execute("w 0x0100-0x10000", &machine),
+            "Address 0x10000 out of range"
+        );
+        assert_eq!(
+            debugger.execute("w 0x0200-0x0100", &machine),
+            "Range 0x0200-0x0100 is backwards"
+        );
+        assert!(debugger.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn counts_memory_accesses() {
+        let mut machine = Machine::with_program(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute("count", &machine),
+            "Not counting accesses, start with 'count on'"
+        );
+        debugger.execute("count on", &machine);
+        debugger.execute("c", &machine);
+        debugger.run_frame(&mut machine);
+        assert_eq!(
+            debugger.execute("count 3", &machine),
+            "0xFDF0: 1 reads, 1 writes\n0xFDF1: 1 reads, 1 writes\n0x0100: 0 reads, 1 writes"
+        );
+    }
+
+    #[test]
+    fn dumps_memory_up_to_the_end() {
+        let machine = Machine::with_program(&PROGRAM);
+        let mut debugger = Debugger::new();
+
+        assert_eq!(
+            debugger.execute("x 0x0010 20", &machine),
+            "0010: 30 00 00 01 15 00 00 00 00 00 00 00 00 00 00 00\n0020: 00 00 00 00"
+        );
+        assert_eq!(
+            debugger.execute("x 0xFFFC 0x20000", &machine),
+            "FFFC: 00 00 00 00"
+        );
+        assert_eq!(
+            debugger.execute("x 0x10000", &machine),
+            "0x10000 is past the end of memory"
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::ops::Bound;
+
+use crate::cpu::{hhll, n, rx, rx_ry, rx_ry_rz};
+
+pub(crate) const CONDITIONS: [&str; 15] = [
+    "z", "nz", "n", "nn", "p", "o", "no", "a", "ae", "b", "be", "g", "ge", "l", "le",
+];
+pub(crate) const JX: [&str; 15] = [
+    "jz", "jnz", "jn", "jnn", "jp", "jo", "jno", "ja", "jae", "jb", "jbe", "jg", "jge", "jl", "jle",
+];
+pub(crate) const CX: [&str; 15] = [
+    "cz", "cnz", "cn", "cnn", "cp", "co", "cno", "ca", "cae", "cb", "cbe", "cg", "cge", "cl", "cle",
+];
+const PALETTE_SIZE: u16 = 16 * 3;
+
+/// Operand layout of an opcode, mirroring the decoders the CPU uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    None,
+    N,
+    Hhll,
+    Code,
+    Palette,
+    Rx,
+    RxRy,
+    RxRyRz,
+    RxHhll,
+    RxRyCode,
+    RxRySprite,
+    RxN,
+    SpHhll,
+    Flip,
+    Sng,
+}
+
+pub(crate) fn lookup(opcode: u8) -> Option<(&'static str, Format)> {
+    return Some(match opcode {
+        0x00 => ("nop", Format::None),
+        0x01 => ("cls", Format::None),
+        0x02 => ("vblnk", Format::None),
+        0x03 => ("bgc", Format::N),
+        0x04 => ("spr", Format::Hhll),
+        0x05 => ("drw", Format::RxRySprite),
+        0x06 => ("drw", Format::RxRyRz),
+        0x07 => ("rnd", Format::RxHhll),
+        0x08 => ("flip", Format::Flip),
+        0x09 => ("snd0", Format::None),
+        0x0A => ("snd1", Format::Hhll),
+        0x0B => ("snd2", Format::Hhll),
+        0x0C => ("snd3", Format::Hhll),
+        0x0D => ("snp", Format::RxHhll),
+        0x0E => ("sng", Format::Sng),
+
+        0x10 => ("jmp", Format::Code),
+        0x11 => ("jmc", Format::Code),
+        0x12 => ("j", Format::Code),
+        0x13 => ("jme", Format::RxRyCode),
+        0x14 => ("call", Format::Code),
+        0x15 => ("ret", Format::None),
+        0x16 => ("jmp", Format::Rx),
+        0x17 => ("c", Format::Code),
+        0x18 => ("call", Format::Rx),
+
+        0x20 => ("ldi", Format::RxHhll),
+        0x21 => ("ldi", Format::SpHhll),
+        0x22 => ("ldm", Format::RxHhll),
+        0x23 => ("ldm", Format::RxRy),
+        0x24 => ("mov", Format::RxRy),
+
+        0x30 => ("stm", Format::RxHhll),
+        0x31 => ("stm", Format::RxRy),
+
+        0x40 => ("addi", Format::RxHhll),
+        0x41 => ("add", Format::RxRy),
+        0x42 => ("add", Format::RxRyRz),
+
+        0x50 => ("subi", Format::RxHhll),
+        0x51 => ("sub", Format::RxRy),
+        0x52 => ("sub", Format::RxRyRz),
+        0x53 => ("cmpi", Format::RxHhll),
+        0x54 => ("cmp", Format::RxRy),
+
+        0x60 => ("andi", Format::RxHhll),
+        0x61 => ("and", Format::RxRy),
+        0x62 => ("and", Format::RxRyRz),
+        0x63 => ("tsti", Format::RxHhll),
+        0x64 => ("tst", Format::RxRy),
+
+        0x70 => ("ori", Format::RxHhll),
+        0x71 => ("or", Format::RxRy),
+        0x72 => ("or", Format::RxRyRz),
+
+        0x80 => ("xori", Format::RxHhll),
+        0x81 => ("xor", Format::RxRy),
+        0x82 => ("xor", Format::RxRyRz),
+
+        0x90 => ("muli", Format::RxHhll),
+        0x91 => ("mul", Format::RxRy),
+        0x92 => ("mul", Format::RxRyRz),
+
+        0xA0 => ("divi", Format::RxHhll),
+        0xA1 => ("div", Format::RxRy),
+        0xA2 => ("div", Format::RxRyRz),
+        0xA3 => ("modi", Format::RxHhll),
+        0xA4 => ("mod", Format::RxRy),
+        0xA5 => ("mod", Format::RxRyRz),
+        0xA6 => ("remi", Format::RxHhll),
+        0xA7 => ("rem", Format::RxRy),
+        0xA8 => ("rem", Format::RxRyRz),
+
+        0xB0 => ("shl", Format::RxN),
+        0xB1 => ("shr", Format::RxN),
+        0xB2 => ("sar", Format::RxN),
+        0xB3 => ("shl", Format::RxRy),
+        0xB4 => ("shr", Format::RxRy),
+        0xB5 => ("sar", Format::RxRy),
+
+        0xC0 => ("push", Format::Rx),
+        0xC1 => ("pop", Format::Rx),
+        0xC2 => ("pushall", Format::None),
+        0xC3 => ("popall", Format::None),
+        0xC4 => ("pushf", Format::None),
+        0xC5 => ("popf", Format::None),
+
+        0xD0 => ("pal", Format::Palette),
+        0xD1 => ("pal", Format::Rx),
+
+        0xE0 => ("noti", Format::RxHhll),
+        0xE1 => ("not", Format::Rx),
+        0xE2 => ("not", Format::RxRy),
+        0xE3 => ("negi", Format::RxHhll),
+        0xE4 => ("neg", Format::Rx),
+        0xE5 => ("neg", Format::RxRy),
+        _ => return None,
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(u8),
+    Sp,
+    Imm(u16),
+    /// A small number such as a shift amount or flip flag.
+    Num(u8),
+    /// A jump or call target.
+    Code(u16),
+    /// The address of sprite data.
+    Sprite(u16),
+    /// The address of a 16 color palette.
+    Palette(u16),
+    /// A raw byte, used for `db` when the opcode is invalid.
+    Byte(u8),
+}
+
+impl Operand {
+    fn write(&self, out: &mut String, labels: &BTreeMap<u16, String>) {
+        let _ = match self {
+            Operand::Reg(reg) => write!(out, "r{:x}", reg),
+            Operand::Sp => write!(out, "sp"),
+            Operand::Imm(value) => write!(out, "0x{:04X}", value),
+            Operand::Num(value) => write!(out, "{}", value),
+            Operand::Byte(value) => write!(out, "0x{:02X}", value),
+            Operand::Code(addr) | Operand::Sprite(addr) | Operand::Palette(addr) => {
+                match labels.get(addr) {
+                    Some(label) => write!(out, "{}", label),
+                    None => write!(out, "0x{:04X}", addr),
+                }
+            }
+        };
+    }
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: [u8; 4],
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Whether the bytes decoded to a real instruction rather than `db`.
+    pub fn is_valid(&self) -> bool {
+        return self.mnemonic != "db";
+    }
+
+    /// The jump or call target, if this instruction has a fixed one.
+    pub fn target(&self) -> Option<u16> {
+        return self.operands.iter().find_map(|operand| match operand {
+            Operand::Code(addr) => Some(*addr),
+            _ => None,
+        });
+    }
+
+    /// Formats the instruction, replacing addresses with labels where known.
+    pub fn format(&self, labels: &BTreeMap<u16, String>) -> String {
+        let mut out = String::from(self.mnemonic);
+        for (index, operand) in self.operands.iter().enumerate() {
+            out.push_str(if index == 0 { " " } else { ", " });
+            operand.write(&mut out, labels);
+        }
+        return out;
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.format(&BTreeMap::new()));
+    }
+}
+
+/// Decodes the instruction at `addr` in `mem`. Bytes past the end of `mem`
+/// read as zero.
+pub fn disassemble(mem: &[u8], addr: u16) -> Instruction {
+    let byte = |offset: usize| -> u8 { *mem.get(addr as usize + offset).unwrap_or(&0) };
+    let bytes = [byte(0), byte(1), byte(2), byte(3)];
+    let invalid = Instruction {
+        addr,
+        bytes,
+        mnemonic: "db",
+        operands: bytes.iter().map(|b| Operand::Byte(*b)).collect(),
+    };
+
+    let (mut mnemonic, format) = match lookup(bytes[0]) {
+        Some(entry) => entry,
+        None => return invalid,
+    };
+
+    if bytes[0] == 0x12 || bytes[0] == 0x17 {
+        let cond = (bytes[1] & 0xF) as usize;
+        if cond >= CONDITIONS.len() {
+            return invalid;
+        }
+        mnemonic = if bytes[0] == 0x12 { JX[cond] } else { CX[cond] };
+    }
+
+    let reg = |index: usize| Operand::Reg(index as u8);
+    let (x, y) = rx_ry(&bytes);
+    let (_, _, z) = rx_ry_rz(&bytes);
+    let imm = hhll(&bytes);
+
+    let operands = match format {
+        Format::None => vec![],
+        Format::N => vec![Operand::Num(n(&bytes))],
+        Format::Hhll => vec![Operand::Imm(imm)],
+        Format::Code => vec![Operand::Code(imm)],
+        Format::Palette => vec![Operand::Palette(imm)],
+        Format::Rx => vec![reg(rx(&bytes))],
+        Format::RxRy => vec![reg(x), reg(y)],
+        Format::RxRyRz => vec![reg(x), reg(y), reg(z)],
+        Format::RxHhll => vec![reg(x), Operand::Imm(imm)],
+        Format::RxRyCode => vec![reg(x), reg(y), Operand::Code(imm)],
+        Format::RxRySprite => vec![reg(x), reg(y), Operand::Sprite(imm)],
+        Format::RxN => vec![reg(x), Operand::Num(n(&bytes))],
+        Format::SpHhll => vec![Operand::Sp, Operand::Imm(imm)],
+        Format::Flip => vec![
+            Operand::Num((bytes[3] >> 1) & 0x1),
+            Operand::Num(bytes[3] & 0x1),
+        ],
+        Format::Sng => vec![Operand::Byte(bytes[1]), Operand::Imm(imm)],
+    };
+
+    return Instruction {
+        addr,
+        bytes,
+        mnemonic,
+        operands,
+    };
+}
+
+/// Disassembles a whole program into labeled assembly. Jump and call targets
+/// get labels, and regions referenced by DRW and PAL are emitted as data,
+/// using the most recent SPR to guess each sprite's size.
+pub fn disassemble_program(program: &[u8], start: u16) -> String {
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+    let mut data: BTreeMap<u16, u16> = BTreeMap::new();
+    let mut sprite_size = 0u16;
+
+    let mut addr = 0usize;
+    while addr < program.len() {
+        let instruction = disassemble(program, addr as u16);
+        match instruction.bytes[0] {
+            0x04 => {
+                sprite_size = instruction.bytes[2] as u16 * instruction.bytes[3] as u16;
+            }
+            0x14 | 0x17 => {
+                let target = hhll(&instruction.bytes);
+                labels.insert(target, format!("sub_{:04X}", target));
+            }
+            _ => {}
+        }
+        for operand in &instruction.operands {
+            match *operand {
+                Operand::Code(target) => {
+                    labels
+                        .entry(target)
+                        .or_insert_with(|| format!("loc_{:04X}", target));
+                }
+                Operand::Sprite(target) if sprite_size > 0 => {
+                    labels.insert(target, format!("spr_{:04X}", target));
+                    let size = data.entry(target).or_insert(0);
+                    *size = (*size).max(sprite_size);
+                }
+                Operand::Palette(target) => {
+                    labels.insert(target, format!("pal_{:04X}", target));
+                    let size = data.entry(target).or_insert(0);
+                    *size = (*size).max(PALETTE_SIZE);
+                }
+                _ => {}
+            }
+        }
+        addr += 4;
+    }
+    labels.insert(start, String::from("start"));
+
+    // Targets past the end of the program can't be labeled in the listing,
+    // so they're defined as constants instead
+    let mut out = String::new();
+    for (addr, label) in &labels {
+        if usize::from(*addr) >= program.len() {
+            let _ = writeln!(out, "{} equ 0x{:04X}", label, addr);
+        }
+    }
+    let mut addr = 0usize;
+    while addr < program.len() {
+        if let Some(label) = labels.get(&(addr as u16)) {
+            let _ = writeln!(out, "{}:", label);
+        }
+
+        let next = labels
+            .range((Bound::Excluded(addr as u16), Bound::Unbounded))
+            .next()
+            .map_or(program.len(), |(next, _)| *next as usize);
+
+        if let Some(size) = data.get(&(addr as u16)).copied() {
+            // Split the region at any label inside it, carrying the rest on
+            // as data after the label
+            let end = (addr + size as usize).min(program.len());
+            let split = next.min(end);
+            if split < end {
+                let rest = data.entry(split as u16).or_insert(0);
+                *rest = (*rest).max((end - split) as u16);
+            }
+            write_data(&mut out, &program[addr..split]);
+            addr = split;
+            continue;
+        }
+
+        // Stop short of the next label or data region so both stay aligned
+        if program.len() - addr < 4 || next - addr < 4 {
+            let end = next.min(program.len());
+            write_data(&mut out, &program[addr..end]);
+            addr = end;
+            continue;
+        }
+
+        let instruction = disassemble(program, addr as u16);
+        let _ = writeln!(
+            out,
+            "    {:<24}; {:04X}: {:02X} {:02X} {:02X} {:02X}",
+            instruction.format(&labels),
+            addr,
+            instruction.bytes[0],
+            instruction.bytes[1],
+            instruction.bytes[2],
+            instruction.bytes[3]
+        );
+        addr += 4;
+    }
+    return out;
+}
+
+fn write_data(out: &mut String, bytes: &[u8]) {
+    for row in bytes.chunks(16) {
+        let row: Vec<String> = row.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        let _ = writeln!(out, "    db {}", row.join(", "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn decodes_operands() {
+        let mem = [
+            0x20, 0x3A, 0x34, 0x12, // ldi ra, 0x1234
+            0x42, 0x21, 0x03, 0x00, // add r1, r2, r3
+            0x12, 0x01, 0x10, 0x00, // jnz 0x0010
+            0x05, 0x10, 0x00, 0x02, // drw r0, r1, 0x0200
+            0x08, 0x00, 0x00, 0x02, // flip 1, 0
+            0xFF, 0x01, 0x02, 0x03, // invalid
+        ];
+        let text: Vec<String> = (0..6)
+            .map(|index| disassemble(&mem, index * 4).to_string())
+            .collect();
+        assert_eq!(
+            text,
+            [
+                "ldi ra, 0x1234",
+                "add r1, r2, r3",
+                "jnz 0x0010",
+                "drw r0, r1, 0x0200",
+                "flip 1, 0",
+                "db 0xFF, 0x01, 0x02, 0x03",
+            ]
+        );
+        assert_eq!(disassemble(&mem, 8).target(), Some(0x0010));
+        assert!(!disassemble(&mem, 20).is_valid());
+    }
+
+    #[test]
+    fn labels_inside_data() {
+        let mut program = vec![
+            0x04, 0x00, 0x02, 0x04, // spr 0x0402
+            0x05, 0x10, 0x10, 0x00, // drw r0, r1, 0x0010
+            0x10, 0x00, 0x14, 0x00, // jmp 0x0014
+            0x15, 0x00, 0x00, 0x00, // ret
+            0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // sprite data
+        ];
+        let listing = disassemble_program(&program, 0);
+        assert!(listing.ends_with(
+            "spr_0010:
+    db 0x11, 0x22, 0x33, 0x44
+loc_0014:
+    db 0x55, 0x66, 0x77, 0x88
+"
+        ));
+
+        // A label on the very last byte of memory
+        program.resize(0x10000, 0);
+        program[8..12].copy_from_slice(&[0x10, 0x00, 0xFF, 0xFF]);
+        let listing = disassemble_program(&program, 0);
+        assert!(listing.ends_with("loc_FFFF:\n    db 0x00\n"));
+    }
+
+    #[test]
+    fn defines_targets_past_the_end() {
+        let program = [
+            0x10, 0x00, 0x00, 0x01, // jmp 0x0100
+            0x14, 0x00, 0x08, 0x00, // call 0x0008
+        ];
+        let listing = disassemble_program(&program, 0);
+        assert_eq!(
+            listing,
+            "sub_0008 equ 0x0008
+loc_0100 equ 0x0100
+start:
+    jmp loc_0100            ; 0000: 10 00 00 01
+    call sub_0008           ; 0004: 14 00 08 00
+"
+        );
+        let assembly = crate::asm::assemble(&listing, Path::new("listing.asm")).unwrap();
+        assert_eq!(assembly.data, program);
+    }
+
+    #[test]
+    fn labels_targets_and_data() {
+        let program = [
+            0x04, 0x00, 0x01, 0x02, // spr 0x0201
+            0x05, 0x10, 0x10, 0x00, // drw r0, r1, 0x0010
+            0x14, 0x00, 0x0C, 0x00, // call 0x000C
+            0x15, 0x00, 0x00, 0x00, // ret
+            0x11, 0x22, // sprite data
+        ];
+        let listing = disassemble_program(&program, 0);
+        assert_eq!(
+            listing,
+            "start:
+    spr 0x0201              ; 0000: 04 00 01 02
+    drw r0, r1, spr_0010    ; 0004: 05 10 10 00
+    call sub_000C           ; 0008: 14 00 0C 00
+sub_000C:
+    ret                     ; 000C: 15 00 00 00
+spr_0010:
+    db 0x11, 0x22
+"
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use std::fmt::Write;
+
+use crate::debugger;
+use crate::disasm::disassemble;
+use crate::symbols::Symbols;
+use crate::Machine;
+
+/// How many of the most recently executed instructions crash reports show.
+pub const HISTORY_LEN: usize = 16;
+
+/// An instruction the machine couldn't execute. The PC is the address of the
+/// faulting instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    InvalidOpcode {
+        pc: u16,
+        op: u8,
+    },
+    InvalidCondition {
+        pc: u16,
+        condition: u8,
+    },
+    DivideByZero {
+        pc: u16,
+    },
+    InvalidWaveform {
+        pc: u16,
+        wave: u8,
+    },
+    /// A push or call with the stack full.
+    StackOverflow {
+        pc: u16,
+        sp: u16,
+    },
+    /// A pop or return with the stack empty.
+    StackUnderflow {
+        pc: u16,
+        sp: u16,
+    },
+}
+
+impl Fault {
+    pub fn pc(&self) -> u16 {
+        return match self {
+            Fault::InvalidOpcode { pc, .. }
+            | Fault::InvalidCondition { pc, .. }
+            | Fault::DivideByZero { pc }
+            | Fault::InvalidWaveform { pc, .. }
+            | Fault::StackOverflow { pc, .. }
+            | Fault::StackUnderflow { pc, .. } => *pc,
+        };
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Fault::InvalidOpcode { pc, op } => {
+                write!(f, "Invalid opcode {:#04X} at {:#06X}", op, pc)
+            }
+            Fault::InvalidCondition { pc, condition } => {
+                write!(f, "Invalid condition {:#X} at {:#06X}", condition, pc)
+            }
+            Fault::DivideByZero { pc } => write!(f, "Division by zero at {:#06X}", pc),
+            Fault::InvalidWaveform { pc, wave } => {
+                write!(f, "Invalid waveform {} at {:#06X}", wave, pc)
+            }
+            Fault::StackOverflow { pc, sp } => {
+                write!(f, "Stack overflow at {:#06X} with SP {:#06X}", pc, sp)
+            }
+            Fault::StackUnderflow { pc, sp } => {
+                write!(f, "Stack underflow at {:#06X} with SP {:#06X}", pc, sp)
+            }
+        };
+    }
+}
+
+/// Describes a fault along with the registers, the call stack and the
+/// instructions that led up to it, the last being the one that faulted.
+pub fn crash_report(machine: &Machine, fault: &Fault, symbols: &Symbols) -> String {
+    let mut out = format!("{}\n{}\n", fault, debugger::registers(machine));
+    let _ = writeln!(out, "Call stack:");
+    let _ = writeln!(
+        out,
+        "{}",
+        debugger::call_stack(machine, fault.pc(), symbols)
+    );
+    let _ = writeln!(out, "Last {} instructions:", machine.history().len());
+    for (index, (pc, bytes)) in machine.history().iter().enumerate() {
+        let marker = if index + 1 == machine.history().len() {
+            '>'
+        } else {
+            ' '
+        };
+        let _ = writeln!(
+            out,
+            "{} {:04X}: {:02X} {:02X} {:02X} {:02X}  {}",
+            marker,
+            pc,
+            bytes[0],
+            bytes[1],
+            bytes[2],
+            bytes[3],
+            disassemble(bytes, 0)
+        );
+    }
+    return out.trim_end().to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timing::Clock;
+
+    fn run(program: &[u8]) -> (Machine, Fault) {
+        let mut machine = Machine::with_program(program);
+        let (fault, _) = machine.run_frame().unwrap_err();
+        return (machine, fault);
+    }
+
+    #[test]
+    fn faults_instead_of_panicking() {
+        // LDI r0, 5; DIV r0, r1
+        let (_, fault) = run(&[0x20, 0x00, 0x05, 0x00, 0xA1, 0x10, 0x00, 0x00]);
+        assert_eq!(fault, Fault::DivideByZero { pc: 0x0004 });
+
+        let (_, fault) = run(&[0x00, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            fault,
+            Fault::InvalidOpcode {
+                pc: 0x0004,
+                op: 0xFF
+            }
+        );
+
+        // Jx with condition 0xF
+        let (_, fault) = run(&[0x12, 0x0F, 0x00, 0x00]);
+        assert_eq!(
+            fault,
+            Fault::InvalidCondition {
+                pc: 0x0000,
+                condition: 0xF
+            }
+        );
+
+        // SNG with wave type 7
+        let (_, fault) = run(&[0x0E, 0x00, 0x00, 0x07]);
+        assert_eq!(
+            fault,
+            Fault::InvalidWaveform {
+                pc: 0x0000,
+                wave: 7
+            }
+        );
+    }
+
+    #[test]
+    fn reports_faults_that_end_the_frame() {
+        // DIV r0, r1
+        let program = [0xA1, 0x10, 0x00, 0x00];
+        let mut machine = Machine::with_program(&program);
+        assert_eq!(
+            machine.step(),
+            Err((Fault::DivideByZero { pc: 0x0000 }, false))
+        );
+
+        // At 60 Hz every instruction ends a frame
+        let mut machine = Machine::with_program(&program);
+        machine.set_clock(Clock::Hz(60));
+        assert_eq!(
+            machine.run_frame(),
+            Err((Fault::DivideByZero { pc: 0x0000 }, true))
+        );
+    }
+
+    #[test]
+    fn faults_when_sp_leaves_the_stack() {
+        // RET with nothing pushed
+        let (_, fault) = run(&[0x15, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            fault,
+            Fault::StackUnderflow {
+                pc: 0x0000,
+                sp: 0xFDF0
+            }
+        );
+
+        // CALL 0x0000 forever
+        let (machine, fault) = run(&[0x14, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            fault,
+            Fault::StackOverflow {
+                pc: 0x0000,
+                sp: 0xFFF0
+            }
+        );
+        assert_eq!(machine.call_stack().len(), 0x100);
+
+        // LDI SP, 0xFFF0; PUSHF
+        let (_, fault) = run(&[0x21, 0x00, 0xF0, 0xFF, 0xC4, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            fault,
+            Fault::StackOverflow {
+                pc: 0x0004,
+                sp: 0xFFF0
+            }
+        );
+
+        // LDI SP, 0xA600 moves the stack; PUSH r0; POP r0; POP r0
+        let (_, fault) = run(&[
+            0x21, 0x00, 0x00, 0xA6, 0xC0, 0x00, 0x00, 0x00, 0xC1, 0x00, 0x00, 0x00, 0xC1, 0x00,
+            0x00, 0x00,
+        ]);
+        assert_eq!(
+            fault,
+            Fault::StackUnderflow {
+                pc: 0x000C,
+                sp: 0xA600
+            }
+        );
+    }
+
+    #[test]
+    fn reports_call_stack_and_recent_instructions() {
+        // CALL 0x0008; LDI r0, 5; DIV r0, r1
+        let (machine, fault) = run(&[
+            0x14, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x05, 0x00, 0xA1, 0x10,
+            0x00, 0x00,
+        ]);
+        let symbols = Symbols::parse("0000 start\n0008 divide").unwrap();
+        let report = crash_report(&machine, &fault, &symbols);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Division by zero at 0x000C");
+        assert_eq!(lines[4], "Call stack:");
+        assert_eq!(lines[5], "#0 000C divide+0x4");
+        assert_eq!(lines[6], "#1 0004 start+0x4  (from FDF0)");
+        assert_eq!(lines[7], "Last 3 instructions:");
+        assert_eq!(lines[8], "  0000: 14 00 08 00  call 0x0008");
+        assert_eq!(lines[10], "> 000C: A1 10 00 00  div r0, r1");
+    }
+}
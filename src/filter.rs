@@ -0,0 +1,179 @@
+use std::fmt;
+
+/// A post-processing filter applied to the RGB frame before it's shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// The frame as is, left for the display to scale up with hard edges.
+    Nearest,
+    /// The frame as is, left for the display to scale up with linear
+    /// filtering, softening the edges.
+    Bilinear,
+    /// Doubled with every second line darkened.
+    Scanlines,
+    /// Scanlines plus a red, green and blue aperture grille.
+    Crt,
+    /// Doubled with Scale2x (EPX), which rounds off diagonal edges in pixel
+    /// art without blurring. It's the simplest of the family hq2x and xBR
+    /// belong to, not either of those.
+    Scale2x,
+}
+
+pub const FILTERS: [Filter; 5] = [
+    Filter::Nearest,
+    Filter::Bilinear,
+    Filter::Scanlines,
+    Filter::Crt,
+    Filter::Scale2x,
+];
+
+/// Brightness of the darkened scanlines, out of 256.
+const SCANLINE_LEVEL: u32 = 160;
+/// Brightness of the two channels an aperture grille column masks, out of 256.
+const MASK_LEVEL: u32 = 180;
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Filter, String> {
+        let lower = filter.trim().to_ascii_lowercase();
+        return FILTERS
+            .into_iter()
+            .find(|candidate| candidate.to_string() == lower)
+            .ok_or_else(|| {
+                format!(
+                    "{} isn't a filter, expected nearest, bilinear, scanlines, crt or scale2x",
+                    filter
+                )
+            });
+    }
+
+    /// The filter after this one, for cycling through them with a hotkey.
+    pub fn next(self) -> Filter {
+        let index = FILTERS
+            .iter()
+            .position(|filter| *filter == self)
+            .unwrap_or(0);
+        return FILTERS[(index + 1) % FILTERS.len()];
+    }
+
+    /// How many times larger than its input the filtered frame is.
+    pub fn scale(self) -> u32 {
+        return match self {
+            Filter::Nearest | Filter::Bilinear => 1,
+            _ => 2,
+        };
+    }
+
+    /// Filters a packed RGB frame of the given size into one `scale()` times
+    /// as large in each direction. Filters left to the display return the
+    /// frame unchanged.
+    pub fn apply(self, rgb: &[u8], width: u32, height: u32) -> Vec<u8> {
+        let (width, height) = (width as usize, height as usize);
+        let pixel = |x: usize, y: usize| {
+            let start = (y.min(height - 1) * width + x.min(width - 1)) * 3;
+            return [rgb[start], rgb[start + 1], rgb[start + 2]];
+        };
+        let (out_width, out_height) = (width * 2, height * 2);
+
+        let mut out = match self {
+            Filter::Nearest | Filter::Bilinear => return rgb.to_vec(),
+            _ => Vec::with_capacity(out_width * out_height * 3),
+        };
+        match self {
+            Filter::Nearest | Filter::Bilinear => {}
+            Filter::Scanlines | Filter::Crt => {
+                for oy in 0..out_height {
+                    let row_level = if oy % 2 == 1 { SCANLINE_LEVEL } else { 256 };
+                    for ox in 0..out_width {
+                        let color = pixel(ox / 2, oy / 2);
+                        for (channel, value) in color.iter().enumerate() {
+                            let mask_level = if self == Filter::Crt && ox % 3 != channel {
+                                MASK_LEVEL
+                            } else {
+                                256
+                            };
+                            out.push((*value as u32 * row_level / 256 * mask_level / 256) as u8);
+                        }
+                    }
+                }
+            }
+            Filter::Scale2x => {
+                let mut rows = [vec![], vec![]];
+                for y in 0..height {
+                    rows[0].clear();
+                    rows[1].clear();
+                    for x in 0..width {
+                        let p = pixel(x, y);
+                        let a = pixel(x, y.saturating_sub(1));
+                        let b = pixel(x + 1, y);
+                        let c = pixel(x.saturating_sub(1), y);
+                        let d = pixel(x, y + 1);
+                        let corners = [
+                            if c == a && c != d && a != b { a } else { p },
+                            if a == b && a != c && b != d { b } else { p },
+                            if d == c && d != b && c != a { c } else { p },
+                            if b == d && b != a && d != c { d } else { p },
+                        ];
+                        rows[0].extend(corners[0].iter().chain(&corners[1]));
+                        rows[1].extend(corners[2].iter().chain(&corners[3]));
+                    }
+                    out.extend(&rows[0]);
+                    out.extend(&rows[1]);
+                }
+            }
+        }
+        return out;
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "{}",
+            match self {
+                Filter::Nearest => "nearest",
+                Filter::Bilinear => "bilinear",
+                Filter::Scanlines => "scanlines",
+                Filter::Crt => "crt",
+                Filter::Scale2x => "scale2x",
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const K: [u8; 3] = [0, 0, 0];
+    const W: [u8; 3] = [200, 200, 200];
+
+    fn image(pixels: &[[u8; 3]]) -> Vec<u8> {
+        return pixels.concat();
+    }
+
+    #[test]
+    fn filters_double_the_frame() {
+        let rgb = image(&[W, K, K, W]);
+        assert_eq!(Filter::Nearest.apply(&rgb, 2, 2), rgb);
+        assert_eq!(Filter::Bilinear.apply(&rgb, 2, 2), rgb);
+
+        // A staircase, whose inside corners Scale2x fills in
+        let stairs = image(&[W, K, K, W, W, K, W, W, W]);
+        let scaled = Filter::Scale2x.apply(&stairs, 3, 3);
+        let at = |x: usize, y: usize| scaled[(y * 6 + x) * 3..(y * 6 + x) * 3 + 3].to_vec();
+        assert_eq!([at(2, 2), at(3, 2), at(2, 3), at(3, 3)], [W, K, W, W]);
+        assert_eq!([at(2, 0), at(3, 0), at(2, 1), at(3, 1)], [K, K, W, K]);
+
+        let scanlines = Filter::Scanlines.apply(&rgb, 2, 2);
+        assert_eq!(scanlines.len(), 4 * 4 * 3);
+        assert_eq!(scanlines[..3], W);
+        assert_eq!(scanlines[4 * 3..4 * 3 + 3], [125, 125, 125]);
+
+        let crt = Filter::Crt.apply(&rgb, 2, 2);
+        assert_eq!(crt[..6], [200, 140, 140, 140, 200, 140]);
+
+        assert_eq!(Filter::parse("CRT"), Ok(Filter::Crt));
+        assert!(Filter::parse("sepia").is_err());
+        assert_eq!(Filter::Scale2x.next(), Filter::Nearest);
+    }
+}
@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::{Machine, GRID_X_SIZE};
+
+/// The palette with index 0 replaced by the background color, since
+/// transparent pixels show the background.
+fn colors(machine: &Machine) -> [u32; 16] {
+    let mut colors = machine.palette();
+    colors[0] = colors[machine.bgc() as usize];
+    return colors;
+}
+
+/// Resolves the screen buffer through the current palette into packed RGB
+/// bytes. Pixels with index 0 are transparent and show the background color.
+pub fn to_rgb(machine: &Machine) -> Vec<u8> {
+    let screen = machine.screen();
+    let colors = colors(machine);
+
+    let mut rgb = Vec::with_capacity(screen.len() * 3);
+    for px in screen.iter() {
+        let color = colors[*px as usize];
+        rgb.push((color >> 16) as u8);
+        rgb.push(((color >> 8) & 0xFF) as u8);
+        rgb.push((color & 0xFF) as u8);
+    }
+    return rgb;
+}
+
+/// Resolves the screen into opaque RGBA bytes in one pass, for filling a
+/// locked texture whose rows are `pitch` bytes apart.
+pub fn write_rgba(machine: &Machine, out: &mut [u8], pitch: usize) {
+    let colors = colors(machine).map(|color| (color << 8 | 0xFF).to_be_bytes());
+    let rows = machine.screen().chunks_exact(GRID_X_SIZE as usize);
+    for (row, out_row) in rows.zip(out.chunks_mut(pitch)) {
+        for (px, out_px) in row.iter().zip(out_row.chunks_exact_mut(4)) {
+            out_px.copy_from_slice(&colors[*px as usize]);
+        }
+    }
+}
+
+/// Writes an RGB frame to `path`, as PPM if the extension is `.ppm` and PNG
+/// otherwise.
+pub fn save(path: &Path, rgb: &[u8], width: u32, height: u32) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut writer = BufWriter::new(file);
+
+    let is_ppm = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ppm"));
+
+    if is_ppm {
+        write!(writer, "P6\n{} {}\n255\n", width, height).map_err(|e| e.to_string())?;
+        writer.write_all(rgb).map_err(|e| e.to_string())?;
+        writer.flush().map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut png_writer = encoder.write_header().map_err(|e| e.to_string())?;
+    png_writer
+        .write_image_data(rgb)
+        .map_err(|e| e.to_string())?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GRID_Y_SIZE;
+
+    #[test]
+    fn rgba_matches_rgb() {
+        // BGC 2; SPR 0x0202; LDI r0, 0; DRW r0, r0, 0x0020 (pixels 4 and 0)
+        let mut program = vec![
+            0x03, 0x00, 0x02, 0x00, 0x04, 0x00, 0x02, 0x02, 0x20, 0x00, 0x00, 0x00, 0x05, 0x00,
+            0x20, 0x00, 0x02, 0x00, 0x00, 0x00,
+        ];
+        program.resize(0x21, 0);
+        program[0x20] = 0x40;
+        let mut machine = Machine::with_program(&program);
+        machine.run_frame().unwrap();
+
+        // Rows padded to 1288 bytes, as a texture's may be
+        let pitch = GRID_X_SIZE as usize * 4 + 8;
+        let mut rgba = vec![0; pitch * GRID_Y_SIZE as usize];
+        write_rgba(&machine, &mut rgba, pitch);
+        assert_eq!(rgba[0..8], [0xDE, 0x7A, 0xAE, 0xFF, 0x88, 0x88, 0x88, 0xFF]);
+        assert_eq!(rgba[pitch - 8..pitch], [0; 8]);
+
+        let rgb = to_rgb(&machine);
+        for (index, rgb_px) in rgb.chunks_exact(3).enumerate() {
+            let (y, x) = (index / GRID_X_SIZE as usize, index % GRID_X_SIZE as usize);
+            let start = y * pitch + x * 4;
+            assert_eq!(rgba[start..start + 3], *rgb_px);
+        }
+    }
+}
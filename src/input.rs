@@ -0,0 +1,204 @@
+use chip16::bindings::{self, Bindings};
+use chip16::cpu::Controller;
+use chip16::Machine;
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::GameControllerSubsystem;
+
+/// How far a stick has to be pushed to count as a press.
+const AXIS_THRESHOLD: i16 = 16384;
+
+/// Keys taken by the emulator's own hotkeys, which can't be bound to a pad.
+const HOTKEYS: [Keycode; 18] = [
+    Keycode::F1,
+    Keycode::F2,
+    Keycode::F3,
+    Keycode::F4,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F7,
+    Keycode::F8,
+    Keycode::F9,
+    Keycode::F10,
+    Keycode::F11,
+    Keycode::F12,
+    Keycode::F,
+    Keycode::Backspace,
+    Keycode::Tab,
+    Keycode::Minus,
+    Keycode::P,
+    Keycode::N,
+];
+
+enum Source {
+    Key(Keycode),
+    Button(Button),
+    Axis(Axis, bool),
+}
+
+struct Binding {
+    source: Source,
+    pad: usize,
+    button: Controller,
+    held: bool,
+}
+
+/// Turns keyboard and gamepad events into the state of both pads.
+pub struct Input {
+    subsystem: GameControllerSubsystem,
+    bindings: Vec<Binding>,
+    /// The gamepads driving pads 1 and 2.
+    gamepads: [Option<GameController>; 2],
+    /// Buttons pressed since the pads were last latched, so that a press and
+    /// release within one frame is still seen by the program.
+    pressed: [Controller; 2],
+}
+
+impl Input {
+    pub fn new(subsystem: GameControllerSubsystem, bindings: &Bindings) -> Result<Input, String> {
+        let mut resolved = vec![];
+        for binding in &bindings.bindings {
+            let source = match &binding.input {
+                bindings::Input::Key(name) => {
+                    let keycode =
+                        Keycode::from_name(name).ok_or_else(|| format!("Unknown key {}", name))?;
+                    if HOTKEYS.contains(&keycode) {
+                        return Err(format!("{} is a hotkey and can't be bound", name));
+                    }
+                    Source::Key(keycode)
+                }
+                bindings::Input::Button(name) => Source::Button(
+                    Button::from_string(name)
+                        .ok_or_else(|| format!("Unknown gamepad button {}", name))?,
+                ),
+                bindings::Input::Axis { axis, positive } => Source::Axis(
+                    Axis::from_string(axis)
+                        .ok_or_else(|| format!("Unknown gamepad axis {}", axis))?,
+                    *positive,
+                ),
+            };
+            resolved.push(Binding {
+                source,
+                pad: binding.pad,
+                button: binding.button,
+                held: false,
+            });
+        }
+        return Ok(Input {
+            subsystem,
+            bindings: resolved,
+            gamepads: [None, None],
+            pressed: [0; 2],
+        });
+    }
+
+    /// Which pad a gamepad drives, if it's one of the two in use.
+    fn gamepad_pad(&self, which: u32) -> Option<usize> {
+        return self.gamepads.iter().position(|gamepad| {
+            gamepad
+                .as_ref()
+                .is_some_and(|gamepad| gamepad.instance_id() == which)
+        });
+    }
+
+    fn set_held(&mut self, matches: impl Fn(&Binding) -> bool, held: bool) {
+        for binding in self.bindings.iter_mut().filter(|b| matches(b)) {
+            if held && !binding.held {
+                self.pressed[binding.pad] |= binding.button;
+            }
+            binding.held = held;
+        }
+    }
+
+    /// Updates the pads from a keyboard or gamepad event.
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::KeyDown {
+                keycode: Some(keycode),
+                repeat: false,
+                ..
+            } => self.set_held(
+                |b| matches!(b.source, Source::Key(k) if k == *keycode),
+                true,
+            ),
+            Event::KeyUp {
+                keycode: Some(keycode),
+                ..
+            } => self.set_held(
+                |b| matches!(b.source, Source::Key(k) if k == *keycode),
+                false,
+            ),
+            Event::ControllerButtonDown { which, button, .. }
+            | Event::ControllerButtonUp { which, button, .. } => {
+                let Some(pad) = self.gamepad_pad(*which) else {
+                    return;
+                };
+                let held = matches!(event, Event::ControllerButtonDown { .. });
+                self.set_held(
+                    |b| b.pad == pad && matches!(b.source, Source::Button(x) if x == *button),
+                    held,
+                );
+            }
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => {
+                let Some(pad) = self.gamepad_pad(*which) else {
+                    return;
+                };
+                for positive in [true, false] {
+                    let held = match positive {
+                        true => *value > AXIS_THRESHOLD,
+                        false => *value < -AXIS_THRESHOLD,
+                    };
+                    self.set_held(
+                        |b| {
+                            b.pad == pad
+                                && matches!(b.source, Source::Axis(x, p) if x == *axis && p == positive)
+                        },
+                        held,
+                    );
+                }
+            }
+            Event::ControllerDeviceAdded { which, .. } => {
+                let Some(pad) = self.gamepads.iter().position(Option::is_none) else {
+                    return;
+                };
+                match self.subsystem.open(*which) {
+                    Ok(gamepad) => {
+                        println!("{} connected as pad {}", gamepad.name(), pad + 1);
+                        self.gamepads[pad] = Some(gamepad);
+                    }
+                    Err(e) => println!("Failed to open gamepad {}: {}", which, e),
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                let Some(pad) = self.gamepad_pad(*which) else {
+                    return;
+                };
+                if let Some(gamepad) = self.gamepads[pad].take() {
+                    println!("{} disconnected from pad {}", gamepad.name(), pad + 1);
+                }
+                self.set_held(
+                    |b| b.pad == pad && !matches!(b.source, Source::Key(_)),
+                    false,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets the pads the program sees for the next frame: everything held,
+    /// plus anything pressed and already released since the last call.
+    pub fn latch(&mut self, cpu: &mut Machine) {
+        for pad in 0..2 {
+            let held = self
+                .bindings
+                .iter()
+                .filter(|b| b.pad == pad && b.held)
+                .fold(0, |buttons, b| buttons | b.button);
+            cpu.set_controller(pad, held | self.pressed[pad]);
+        }
+        self.pressed = [0; 2];
+    }
+}
@@ -0,0 +1,31 @@
+#![allow(clippy::needless_return)]
+
+pub mod asm;
+pub mod bindings;
+pub mod bus;
+pub mod capture;
+pub mod compat;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod fault;
+pub mod filter;
+pub mod frame;
+pub mod movie;
+pub mod rewind;
+pub mod rom;
+pub mod scale;
+pub mod sound;
+pub mod state;
+pub mod symbols;
+pub mod timing;
+pub mod trace;
+pub mod wav;
+
+pub use cpu::Machine;
+
+pub const GRID_X_SIZE: u32 = 320;
+pub const GRID_Y_SIZE: u32 = 240;
+pub const CLOCK_RATE: u32 = 1000000;
+pub const FPS: u32 = 60;
+pub const AUDIO_SAMPLE_RATE: i32 = 48_000;
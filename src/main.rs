@@ -1,143 +1,556 @@
-#[macro_export]
-macro_rules! dbg_println {
-    ($($arg:tt)*) => (#[cfg(debug_assertions)] println!($($arg)*));
-}
-macro_rules! instr_dbg_println {
-    ($($arg:tt)*) => {};
-}
+#![allow(clippy::needless_return)]
 
+extern crate chip16;
+#[cfg(feature = "sdl")]
 extern crate sdl2;
 
+#[cfg(feature = "sdl")]
 mod audio;
-mod cpu;
+#[cfg(feature = "sdl")]
+mod input;
+#[cfg(feature = "sdl")]
 mod renderer;
+#[cfg(feature = "sdl")]
+mod speed;
+#[cfg(feature = "sdl")]
+mod window;
+
+use chip16::capture::Capture;
+use chip16::fault::{crash_report, Fault};
+use chip16::filter::Filter;
+use chip16::frame;
+use chip16::movie::Movie;
+use chip16::rom::{self, Rom};
+use chip16::scale::ScaleMode;
+use chip16::sound::{NullSound, Sound};
+use chip16::state::State;
+use chip16::symbols::Symbols;
+use chip16::timing::Clock;
+use chip16::trace::{self, Tracer};
+use chip16::{asm, compat, disasm};
+use chip16::{Machine, GRID_X_SIZE, GRID_Y_SIZE};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::Path;
+
+/// What to do when the ROM executes an instruction that faults.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPolicy {
+    /// Print a crash report and stop emulating
+    Halt,
+    /// Print the fault and carry on with the next instruction, counting repeats of it
+    Continue,
+    /// Print a crash report and pause in the debugger (halts when headless)
+    Break,
+}
+
+/// Faults carried past with `--on-fault continue`. Each is printed the first
+/// time it happens and only counted after that, so one in a loop doesn't
+/// flood the console.
+#[derive(Default)]
+pub struct ContinuedFaults {
+    counts: Vec<(Fault, u64)>,
+}
+
+impl ContinuedFaults {
+    fn add(&mut self, fault: Fault) {
+        match self.counts.iter_mut().find(|(seen, _)| *seen == fault) {
+            Some((_, count)) => *count += 1,
+            None => {
+                println!("{}", fault);
+                self.counts.push((fault, 1));
+            }
+        }
+    }
+
+    fn print_repeats(&self) {
+        for (fault, count) in self.counts.iter().filter(|(_, count)| *count > 1) {
+            println!("{} happened {} times", fault, count);
+        }
+    }
+}
 
-use binrw::binread;
-use binrw::io::{Cursor, Seek};
-use binrw::BinReaderExt; // extension traits for use with readers and writers // A no_std reimplementation of std::io
-
-use clap::Parser;
-use cpu::CPU;
-use rand::Rng;
-use renderer::Renderer;
-use sdl2::audio::AudioSpecDesired;
-use std::fs::File;
-use std::io::Read;
-
-const GRID_X_SIZE: u32 = 320;
-const GRID_Y_SIZE: u32 = 240;
-const DOT_SIZE_IN_PXS: u32 = 2;
-const CLOCK_RATE: u32 = 1000000;
-const FPS: u32 = 60;
-const FRAME_CYCLES: u32 = CLOCK_RATE / FPS;
-const AUDIO_SAMPLE_RATE: i32 = 48_000;
-
-#[binread]
-pub struct ROMHeader {
-    magic_number: [u8; 4],
-    reserved: u8,
-    specification_version: u8,
-    rom_size: u32,
-    start_address: [u8; 2],
-    rom_crc: [u8; 4],
-}
-
-pub fn parse_rom(path: String) -> Result<(), String> {
-    let mut header = [0; 16];
-    let mut has_header: bool;
-    let mut mem = [0; 65536];
-
-    {
-        let mut rom = File::open(path).expect("Should have been able to open the file");
-        rom.read(header.as_mut_slice())
-            .expect("Should have been able to read the rom");
-
-        has_header = [header[0], header[1], header[2], header[3]]
-            == ['C' as u8, 'H' as u8, '1' as u8, '6' as u8];
-
-        if has_header {
-            rom.seek(std::io::SeekFrom::Start(16))
-                .expect("Should have been able to seek to 16 in the rom");
-        } else {
-            rom.seek(std::io::SeekFrom::Start(0))
-                .expect("Should have been able to seek to 16 in the rom");
+/// Runs a frame, carrying on past faults if the policy says to. Returns the
+/// fault that stopped it otherwise.
+fn run_frame(
+    cpu: &mut Machine,
+    policy: FaultPolicy,
+    continued: &mut ContinuedFaults,
+) -> Result<(), Fault> {
+    loop {
+        match cpu.run_frame() {
+            Ok(()) => return Ok(()),
+            Err((fault, frame_done)) if policy == FaultPolicy::Continue => {
+                continued.add(fault);
+                if frame_done {
+                    return Ok(());
+                }
+            }
+            Err((fault, _)) => return Err(fault),
         }
-        rom.read(mem.as_mut_slice())
-            .expect("Should have been able to read the rom");
     }
+}
 
-    let sdl_context = sdl2::init()?;
-    let video_subsystem = sdl_context.video()?;
-    let audio_subsystem = sdl_context.audio()?;
+/// A movie being recorded, or played back along with the next frame to play.
+pub enum MovieMode {
+    Record(Movie),
+    Play(Movie, usize),
+}
 
-    let desired_spec = AudioSpecDesired {
-        freq: Some(AUDIO_SAMPLE_RATE),
-        channels: Some(1),
-        // mono  -
-        samples: Some(1024),
+impl MovieMode {
+    /// Records or replays the input for the frame about to run.
+    fn before_frame(&mut self, cpu: &mut Machine) {
+        match self {
+            MovieMode::Record(movie) => movie.record(cpu),
+            MovieMode::Play(movie, frame) => {
+                if movie.play(*frame, cpu) {
+                    *frame += 1;
+                } else if *frame == movie.frames.len() {
+                    println!("Movie finished after {} frames", frame);
+                    *frame += 1;
+                }
+            }
+        }
+    }
+
+    /// Drops the newest recorded frame when rewinding during a recording.
+    #[cfg(feature = "sdl")]
+    fn rewind(&mut self) {
+        if let MovieMode::Record(movie) = self {
+            movie.frames.pop();
+        }
+    }
+
+    fn finish(&self, args: &Args) -> Result<(), String> {
+        if let (MovieMode::Record(movie), Some(path)) = (self, &args.record) {
+            movie.save(Path::new(path))?;
+            println!("Recorded {} frames to {}", movie.frames.len(), path);
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_rom(
+    args: &Args,
+    audio: Box<dyn Sound>,
+) -> Result<(Machine, Option<MovieMode>), String> {
+    let rom_path = args.rom_path.as_deref().unwrap_or_default();
+    let rom = Rom::load(Path::new(rom_path), !args.ignore_crc).map_err(|e| e.to_string())?;
+
+    if let Some((major, minor)) = rom.spec_version() {
+        println!("{}: Chip16 spec {}.{}", rom_path, major, minor);
+    }
+
+    let mut machine = Machine::from_rom(&rom, audio);
+    if let Some(spec) = args.spec {
+        machine.set_spec(spec);
+    }
+    machine.set_allow_newer_opcodes(args.allow_newer_opcodes);
+    machine.set_clock(args.clock);
+    if let Some(path) = &args.load_state {
+        machine.restore(&State::load(Path::new(path))?);
+    }
+
+    if let Some(path) = &args.trace {
+        let tracer = Tracer::create(
+            Path::new(path),
+            args.trace_format,
+            args.trace_start,
+            args.trace_stop,
+        )?;
+        machine.set_tracer(Some(tracer));
+    }
+
+    let movie = if let Some(path) = &args.play {
+        let movie = Movie::load(Path::new(path))?;
+        if !movie.matches(&rom) {
+            println!("Warning: {} was recorded on a different ROM", path);
+        }
+        if (movie.clock, movie.spec) != (machine.timing().clock(), machine.spec()) {
+            println!(
+                "Playing {} back at {} as spec {}.{}, as it was recorded",
+                path, movie.clock, movie.spec.0, movie.spec.1
+            );
+        }
+        if movie.allow_newer_opcodes != machine.allow_newer_opcodes() {
+            println!(
+                "Playing {} back {} newer opcodes, as it was recorded",
+                path,
+                if movie.allow_newer_opcodes {
+                    "allowing"
+                } else {
+                    "without"
+                }
+            );
+        }
+        movie.prepare(&mut machine);
+        Some(MovieMode::Play(movie, 0))
+    } else if args.record.is_some() {
+        let seed = args.seed.unwrap_or_else(rand::random);
+        machine.seed_rng(seed);
+        Some(MovieMode::Record(Movie::new(seed, &rom, &machine)))
+    } else {
+        if let Some(seed) = args.seed {
+            machine.seed_rng(seed);
+        }
+        None
     };
+    return Ok((machine, movie));
+}
+
+fn load_symbols(args: &Args) -> Result<Symbols, String> {
+    return match &args.symbols {
+        Some(path) => Symbols::load(Path::new(path)),
+        None => Ok(Symbols::default()),
+    };
+}
+
+fn print_warnings(cpu: &mut Machine) {
+    for warning in cpu.take_warnings() {
+        println!("Warning: {}", warning);
+    }
+}
 
-    let mut output_file =
-        File::create("cpu_audio_output").expect("Failed to open audio file for writing");
-
-    let mut audio_device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-        audio::default_wave(&mut output_file)
-    })?;
-
-    let mut audio_state = audio::AudioState::new(&mut audio_device);
-
-    let mut event_pump = sdl_context.event_pump()?;
-
-    let window = video_subsystem
-        .window(
-            "snake-game",
-            GRID_X_SIZE * DOT_SIZE_IN_PXS,
-            GRID_Y_SIZE * DOT_SIZE_IN_PXS,
-        )
-        .position_centered()
-        .opengl()
-        .build()
-        .map_err(|e| e.to_string())?;
-
-    let mut renderer = Renderer::new(window)?;
-
-    let mut cpu = CPU::new(&mut mem, &mut event_pump, &mut audio_state);
-
-    if has_header {
-        let mut reader = Cursor::new(header);
-        let header: ROMHeader = reader.read_le().unwrap();
-        instr_dbg_println!(
-            "Header: magic_number: {:#02X?}\nreserved: {:#02X?}\nspec: {:#02X?}\nrom_size: {:#02X?}\nstart_address: {:#02X?}\ncrc: {:#02X?}",
-            header.magic_number,
-            header.reserved,
-            header.specification_version,
-            header.rom_size,
-            header.start_address,
-            header.rom_crc
+fn create_capture(args: &Args) -> Result<Option<Capture>, String> {
+    return args
+        .capture
+        .as_deref()
+        .map(|path| Capture::create(Path::new(path)))
+        .transpose();
+}
+
+fn finish_capture(args: &Args, capture: Option<Capture>) -> Result<(), String> {
+    if let Some(capture) = capture {
+        let frames = capture.frames();
+        capture.finish()?;
+        println!(
+            "Captured {} frames to {}",
+            frames,
+            args.capture.as_deref().unwrap_or_default()
         );
+    }
+    return Ok(());
+}
 
-        cpu.set_pc(header.start_address);
-    } else {
-        // println!("No header");
+/// Runs the ROM for a fixed number of frames without a window or audio
+/// device, optionally saving the last frame.
+pub fn run_headless(args: &Args) -> Result<(), String> {
+    let (mut cpu, mut movie) = parse_rom(args, Box::new(NullSound))?;
+    let symbols = load_symbols(args)?;
+
+    // A movie being played back runs to its end
+    let frames = match &movie {
+        Some(MovieMode::Play(movie, _)) => movie.frames.len() as u32,
+        _ => args.frames,
+    };
+    let mut capture = create_capture(args)?;
+    let mut continued = ContinuedFaults::default();
+    let mut fault = None;
+    for _ in 0..frames {
+        if let Some(movie) = &mut movie {
+            movie.before_frame(&mut cpu);
+        }
+        let result = run_frame(&mut cpu, args.on_fault, &mut continued);
+        print_warnings(&mut cpu);
+        if let Some(capture) = &mut capture {
+            capture.add_frame(&cpu)?;
+        }
+        if let Err(error) = result {
+            fault = Some(error);
+            break;
+        }
+    }
+    continued.print_repeats();
+    if let Some(movie) = &movie {
+        movie.finish(args)?;
+    }
+    if let Some(tracer) = cpu.set_tracer(None) {
+        tracer.finish()?;
+    }
+    finish_capture(args, capture)?;
+    if let Some(fault) = fault {
+        return Err(crash_report(&cpu, &fault, &symbols));
+    }
+
+    if let Some(path) = &args.screenshot {
+        let rgb = args
+            .filter
+            .apply(&frame::to_rgb(&cpu), GRID_X_SIZE, GRID_Y_SIZE);
+        let scale = args.filter.scale();
+        frame::save(
+            Path::new(path),
+            &rgb,
+            GRID_X_SIZE * scale,
+            GRID_Y_SIZE * scale,
+        )?;
     }
 
-    cpu.init();
-    cpu.run(&mut renderer)?;
     Ok(())
 }
 
-/// Simple program to greet a person
+/// Writes a labeled disassembly of a ROM to `output`, or stdout.
+pub fn run_disasm(rom_path: &str, output: Option<&str>, ignore_crc: bool) -> Result<(), String> {
+    let rom = Rom::load(Path::new(rom_path), !ignore_crc).map_err(|e| e.to_string())?;
+
+    let mut listing = String::new();
+    if let Some((major, minor)) = rom.spec_version() {
+        listing.push_str(&format!(
+            "; {}: Chip16 spec {}.{}\n",
+            rom_path, major, minor
+        ));
+    }
+    listing.push_str(&format!("; start at {:#06X}\n", rom.start()));
+    listing.push_str(&disasm::disassemble_program(rom.data(), rom.start()));
+
+    match output {
+        Some(path) => std::fs::write(path, listing).map_err(|e| e.to_string())?,
+        None => print!("{}", listing),
+    }
+    Ok(())
+}
+
+/// Runs every ROM in a pack and writes a compatibility report to `output`, or
+/// stdout.
+pub fn run_compat(pack: &str, frames: u32, output: Option<&str>) -> Result<(), String> {
+    let roms = compat::read_pack(Path::new(pack))?;
+    if roms.is_empty() {
+        return Err(format!("No .c16 ROMs found in {}", pack));
+    }
+    let report = compat::run(roms, frames);
+
+    match output {
+        Some(path) if path.to_ascii_lowercase().ends_with(".json") => {
+            std::fs::write(path, report.to_json() + "\n").map_err(|e| e.to_string())?
+        }
+        Some(path) => std::fs::write(path, report.to_markdown()).map_err(|e| e.to_string())?,
+        None => print!("{}", report.to_markdown()),
+    }
+    Ok(())
+}
+
+/// Assembles tchip16 style source into a ROM, with a CH16 header unless `raw`.
+pub fn run_asm(
+    source: &str,
+    output: &str,
+    spec: (u8, u8),
+    raw: bool,
+    symbols: Option<&str>,
+) -> Result<(), String> {
+    let assembly = asm::assemble_file(Path::new(source))?;
+    if let Some(path) = symbols {
+        assembly.labels.save(Path::new(path))?;
+    }
+    let bytes = if raw {
+        assembly.data
+    } else {
+        assembly.to_rom(spec).to_bytes()
+    };
+    std::fs::write(output, bytes).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// A Chip16 emulator
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// Name of the person to greet
-    #[arg(short, long)]
-    rom_path: String,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to the ROM to run
+    #[arg(short, long, required = true)]
+    rom_path: Option<String>,
+
+    /// Load ROMs whose CRC-32 doesn't match their header
+    #[arg(long)]
+    ignore_crc: bool,
+
+    /// Start paused in the interactive debugger, reading commands from stdin
+    #[arg(long, conflicts_with = "headless")]
+    debug: bool,
+
+    /// Run without a window or audio device
+    #[arg(long)]
+    headless: bool,
+
+    /// Run as this spec version instead of the one in the ROM header, e.g. 1.1
+    #[arg(long, value_parser = rom::parse_spec_version)]
+    spec: Option<(u8, u8)>,
+
+    /// Run instructions newer than the spec with a warning instead of faulting
+    #[arg(long)]
+    allow_newer_opcodes: bool,
+
+    /// CPU clock speed, such as 1mhz or 2mhz, or unlimited to run until each VBLNK
+    #[arg(long, default_value = "1mhz", value_parser = Clock::parse)]
+    clock: Clock,
+
+    /// Number of frames to run in headless mode
+    #[arg(long, default_value_t = 600)]
+    frames: u32,
+
+    /// Save the last frame of a headless run (PNG, or PPM with a .ppm extension)
+    #[arg(long, requires = "headless")]
+    screenshot: Option<String>,
+
+    /// Initial window size as a multiple of the 320x240 screen
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..=16))]
+    scale: u32,
+
+    /// How the screen fills the window: integer, fit or stretch
+    #[arg(long, default_value = "integer", value_parser = ScaleMode::parse)]
+    scale_mode: ScaleMode,
+
+    /// Start in fullscreen
+    #[arg(long)]
+    fullscreen: bool,
+
+    /// Filter to draw with, also applied to headless screenshots: nearest, bilinear, scanlines, crt or
+    /// scale2x (Scale2x/EPX, not hq2x or xBR)
+    #[arg(long, default_value = "nearest", value_parser = Filter::parse)]
+    filter: Filter,
+
+    /// Record every frame to an animated GIF, or to raw video with a .y4m extension
+    #[arg(long)]
+    capture: Option<String>,
+
+    /// Record the sound played to a WAV file
+    #[arg(long, conflicts_with = "headless")]
+    record_audio: Option<String>,
+
+    /// Seconds of play to keep for rewinding with Backspace, 0 to disable
+    #[arg(long, default_value_t = 10)]
+    rewind: u32,
+
+    /// Start from a save state instead of the beginning of the ROM
+    #[arg(long, conflicts_with_all = ["record", "play"])]
+    load_state: Option<String>,
+
+    /// Seed for the RND instruction, random if not given
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Record the seed and controller input to a movie file
+    #[arg(long, conflicts_with_all = ["play", "debug"])]
+    record: Option<String>,
+
+    /// Play back a movie recorded with --record
+    #[arg(long, conflicts_with_all = ["seed", "debug"])]
+    play: Option<String>,
+
+    /// Speed multiplier while Tab is held to fast-forward, uncapped if not given
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    fast_forward: Option<u32>,
+
+    /// Log every executed instruction with the registers and flags to a file
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Trace line layout: default, or mash16 to compare with traces from mash16
+    #[arg(long, requires = "trace", default_value = "default", value_parser = trace::Format::parse)]
+    trace_format: trace::Format,
+
+    /// Start tracing at an address or frame, such as pc:0x0244 or frame:120
+    #[arg(long, requires = "trace", value_parser = trace::Condition::parse)]
+    trace_start: Option<trace::Condition>,
+
+    /// Stop tracing at an address or frame
+    #[arg(long, requires = "trace", value_parser = trace::Condition::parse)]
+    trace_stop: Option<trace::Condition>,
+
+    /// TOML file mapping keys and gamepad buttons to both pads
+    #[arg(long)]
+    bindings: Option<String>,
+
+    /// Symbol file naming addresses in the debugger and crash reports, as written by asm --symbols
+    #[arg(long)]
+    symbols: Option<String>,
+
+    /// What to do when the ROM executes an invalid instruction or divides by zero
+    #[arg(long, value_enum, default_value_t = FaultPolicy::Halt)]
+    on_fault: FaultPolicy,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Disassemble a ROM into labeled assembly
+    Disasm {
+        /// Path to the ROM to disassemble
+        rom: String,
+
+        /// Write the listing to a file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Disassemble ROMs whose CRC-32 doesn't match their header
+        #[arg(long)]
+        ignore_crc: bool,
+    },
+    /// Assemble tchip16 style source into a ROM
+    Asm {
+        /// Path to the source file
+        source: String,
+
+        /// Path to write the ROM to
+        #[arg(short, long)]
+        output: String,
+
+        /// Specification version to declare in the header
+        #[arg(long, default_value = "1.3", value_parser = rom::parse_spec_version)]
+        spec: (u8, u8),
+
+        /// Write the program without a CH16 header
+        #[arg(long)]
+        raw: bool,
+
+        /// Also write the labels to a symbol file for the debugger
+        #[arg(long)]
+        symbols: Option<String>,
+    },
+    /// Run every ROM in a pack headless and report how far each got
+    Compat {
+        /// Zip archive or directory of ROMs
+        #[arg(default_value = "Chip16 program pack 09.04.2018.zip")]
+        pack: String,
+
+        /// Frames to run each ROM for
+        #[arg(long, default_value_t = 600)]
+        frames: u32,
+
+        /// Write the report to a file instead of stdout, as JSON if the
+        /// extension is .json and markdown otherwise
+        #[arg(short, long)]
+        output: Option<String>,
+    },
 }
 
 pub fn main() -> Result<(), String> {
     let args = Args::parse();
-    parse_rom(args.rom_path)?;
+    match &args.command {
+        Some(Command::Disasm {
+            rom,
+            output,
+            ignore_crc,
+        }) => run_disasm(rom, output.as_deref(), *ignore_crc)?,
+        Some(Command::Asm {
+            source,
+            output,
+            spec,
+            raw,
+            symbols,
+        }) => run_asm(source, output, *spec, *raw, symbols.as_deref())?,
+        Some(Command::Compat {
+            pack,
+            frames,
+            output,
+        }) => run_compat(pack, *frames, output.as_deref())?,
+        None if args.headless => run_headless(&args)?,
+        #[cfg(feature = "sdl")]
+        None => window::run_window(&args)?,
+        #[cfg(not(feature = "sdl"))]
+        None => {
+            return Err("Built without SDL, so ROMs can only be run with --headless".to_string())
+        }
+    }
 
     Ok(())
 }
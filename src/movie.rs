@@ -0,0 +1,186 @@
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::path::Path;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::cpu::Controller;
+use crate::rom::Rom;
+use crate::timing::Clock;
+use crate::Machine;
+
+const MAGIC: &[u8; 4] = b"C16M";
+pub const VERSION: u16 = 3;
+
+/// A recorded session: the RNG seed, clock, spec and opcode strictness the
+/// machine started with and the state of both pads at the start of every frame. Replaying it on the
+/// same ROM reproduces the session exactly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub seed: u64,
+    /// CRC-32 of the program the movie was recorded on.
+    pub rom_crc: u32,
+    pub clock: Clock,
+    pub spec: (u8, u8),
+    pub allow_newer_opcodes: bool,
+    pub frames: Vec<[Controller; 2]>,
+}
+
+impl Movie {
+    /// Starts a movie of `rom` running on `machine`, whose clock, spec and
+    /// opcode strictness it records.
+    pub fn new(seed: u64, rom: &Rom, machine: &Machine) -> Movie {
+        return Movie {
+            seed,
+            rom_crc: crc32fast::hash(rom.data()),
+            clock: machine.timing().clock(),
+            spec: machine.spec(),
+            allow_newer_opcodes: machine.allow_newer_opcodes(),
+            frames: vec![],
+        };
+    }
+
+    /// Whether the movie was recorded on this ROM.
+    pub fn matches(&self, rom: &Rom) -> bool {
+        return self.rom_crc == crc32fast::hash(rom.data());
+    }
+
+    /// Seeds the machine and runs it at the clock and spec the movie was
+    /// recorded at, allowing newer opcodes if the recording did.
+    pub fn prepare(&self, machine: &mut Machine) {
+        machine.seed_rng(self.seed);
+        machine.set_clock(self.clock);
+        machine.set_spec(self.spec);
+        machine.set_allow_newer_opcodes(self.allow_newer_opcodes);
+    }
+
+    /// Records the pads as they are before the next frame runs.
+    pub fn record(&mut self, machine: &Machine) {
+        self.frames.push(machine.controllers());
+    }
+
+    /// Applies the input recorded for `frame`, returning false once the movie
+    /// has run out.
+    pub fn play(&self, frame: usize, machine: &mut Machine) -> bool {
+        let Some(pads) = self.frames.get(frame) else {
+            return false;
+        };
+        machine.set_controller(0, pads[0]);
+        machine.set_controller(1, pads[1]);
+        return true;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.frames.len() * 2 + 32);
+        out.extend_from_slice(MAGIC);
+        out.write_u16::<LE>(VERSION).unwrap();
+        out.write_u64::<LE>(self.seed).unwrap();
+        out.write_u32::<LE>(self.rom_crc).unwrap();
+        // An unlimited clock is stored as 0 Hz
+        out.write_u32::<LE>(match self.clock {
+            Clock::Hz(hz) => hz,
+            Clock::Unlimited => 0,
+        })
+        .unwrap();
+        out.write_u8(self.spec.0).unwrap();
+        out.write_u8(self.spec.1).unwrap();
+        out.write_u8(u8::from(self.allow_newer_opcodes)).unwrap();
+        out.write_u32::<LE>(self.frames.len() as u32).unwrap();
+        for pads in &self.frames {
+            out.extend_from_slice(pads);
+        }
+        return out;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, String> {
+        return read_movie(&mut Cursor::new(bytes)).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => String::from("Movie is truncated"),
+            _ => e.to_string(),
+        });
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        return std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e));
+    }
+
+    pub fn load(path: &Path) -> Result<Movie, String> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        return Movie::from_bytes(&bytes);
+    }
+}
+
+fn read_movie(input: &mut Cursor<&[u8]>) -> std::io::Result<Movie> {
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a Chip16 movie"));
+    }
+    let version = input.read_u16::<LE>()?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Movie is version {}, this build reads version {}",
+                version, VERSION
+            ),
+        ));
+    }
+
+    let seed = input.read_u64::<LE>()?;
+    let rom_crc = input.read_u32::<LE>()?;
+    let clock = match input.read_u32::<LE>()? {
+        0 => Clock::Unlimited,
+        hz => Clock::Hz(hz),
+    };
+    let spec = (input.read_u8()?, input.read_u8()?);
+    let allow_newer_opcodes = input.read_u8()? != 0;
+    let mut frames = vec![[0; 2]; input.read_u32::<LE>()? as usize];
+    for pads in frames.iter_mut() {
+        input.read_exact(pads)?;
+    }
+    return Ok(Movie {
+        seed,
+        rom_crc,
+        clock,
+        spec,
+        allow_newer_opcodes,
+        frames,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::NullSound;
+
+    #[test]
+    fn replays_newer_opcodes_it_was_recorded_with() {
+        // NOTI r1, 0 (spec 1.3); VBLNK; JMP 0x0000
+        let program = vec![
+            0xE0, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+        ];
+        let rom = Rom::with_header((1, 1), 0, program);
+
+        let mut recording = Machine::from_rom(&rom, Box::new(NullSound));
+        recording.seed_rng(1);
+        recording.set_allow_newer_opcodes(true);
+        let mut movie = Movie::new(1, &rom, &recording);
+        for _ in 0..3 {
+            movie.record(&recording);
+            recording.run_frame().unwrap();
+        }
+
+        let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert!(movie.allow_newer_opcodes);
+        let mut playback = Machine::from_rom(&rom, Box::new(NullSound));
+        movie.prepare(&mut playback);
+        let mut frame = 0;
+        while movie.play(frame, &mut playback) {
+            playback.run_frame().unwrap();
+            frame += 1;
+        }
+        assert_eq!(playback.registers()[1], -1);
+        assert!(playback.state() == recording.state());
+    }
+}
@@ -1,80 +1,155 @@
+use chip16::filter::Filter;
+use chip16::scale::{viewport, ScaleMode};
+use chip16::{frame, Machine, GRID_X_SIZE, GRID_Y_SIZE};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
-use sdl2::render::WindowCanvas;
-use sdl2::video::Window;
-use sdl2::EventPump;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::{FullscreenType, WindowContext};
 
-use crate::cpu::CPU;
-use crate::{DOT_SIZE_IN_PXS, GRID_X_SIZE, GRID_Y_SIZE};
-
-pub struct Renderer {
+/// Draws the screen scaled to the window, letterboxed in black. F cycles the
+/// filter, F10 the scale mode and F11 toggles fullscreen.
+pub struct Renderer<'a> {
     canvas: WindowCanvas,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    /// The filtered screen, refilled every frame.
+    texture: Texture<'a>,
+    mode: ScaleMode,
+    filter: Filter,
+}
+
+/// Unfiltered frames are written straight into an RGBA texture, which SDL
+/// scales with linear filtering for the bilinear filter. The other filters'
+/// frames are uploaded as RGB at the filter's size.
+fn create_texture<'a>(
+    texture_creator: &'a TextureCreator<WindowContext>,
+    filter: Filter,
+) -> Result<Texture<'a>, String> {
+    // Read by SDL when the texture is created
+    let scale_quality = match filter {
+        Filter::Bilinear => "linear",
+        _ => "nearest",
+    };
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", scale_quality);
+    let format = match filter {
+        Filter::Nearest | Filter::Bilinear => PixelFormatEnum::RGBA32,
+        _ => PixelFormatEnum::RGB24,
+    };
+    texture_creator
+        .create_texture_streaming(
+            format,
+            GRID_X_SIZE * filter.scale(),
+            GRID_Y_SIZE * filter.scale(),
+        )
+        .map_err(|e| e.to_string())
 }
 
-impl Renderer {
-    pub fn new(window: Window) -> Result<Renderer, String> {
-        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-        Ok(Renderer { canvas })
+impl<'a> Renderer<'a> {
+    pub fn new(
+        mut canvas: WindowCanvas,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        mode: ScaleMode,
+        filter: Filter,
+    ) -> Result<Renderer<'a>, String> {
+        canvas
+            .window_mut()
+            .set_minimum_size(GRID_X_SIZE, GRID_Y_SIZE)
+            .map_err(|e| e.to_string())?;
+        let texture = create_texture(texture_creator, filter)?;
+        Ok(Renderer {
+            canvas,
+            texture_creator,
+            texture,
+            mode,
+            filter,
+        })
     }
-    fn draw_dot(&mut self, x: u32, y: u32) -> Result<(), String> {
-        self.canvas.fill_rect(Rect::new(
-            (x * DOT_SIZE_IN_PXS) as i32,
-            (y * DOT_SIZE_IN_PXS) as i32,
-            DOT_SIZE_IN_PXS,
-            DOT_SIZE_IN_PXS,
-        ))?;
 
+    pub fn set_filter(&mut self, filter: Filter) -> Result<(), String> {
+        self.texture = create_texture(self.texture_creator, filter)?;
+        self.filter = filter;
         Ok(())
     }
 
-    fn draw_foreground(&mut self, cpu: &mut CPU) -> Result<(), String> {
-        let screen = cpu.screen();
-        let y_range: std::ops::Range<u32> = std::ops::Range {
-            start: 0,
-            end: GRID_Y_SIZE as u32,
+    pub fn set_title(&mut self, title: &str) -> Result<(), String> {
+        self.canvas
+            .window_mut()
+            .set_title(title)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn set_fullscreen(&mut self, fullscreen: bool) -> Result<(), String> {
+        let fullscreen_type = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
         };
+        self.canvas.window_mut().set_fullscreen(fullscreen_type)
+    }
 
-        for y in y_range {
-            let x_range: std::ops::Range<u32> = std::ops::Range {
-                start: 0,
-                end: GRID_X_SIZE as u32,
-            };
-            for x in x_range {
-                let px = screen[(y * GRID_X_SIZE as u32 + x) as usize];
-                // 0 is transparent
-                if px > 0 {
-                    let draw_color = self.get_draw_color(cpu, px);
-                    self.canvas.set_draw_color(draw_color);
-                    self.draw_dot(x as u32, y as u32);
-                }
+    /// Handles the display hotkeys, returning true if the event was one.
+    pub fn handle(&mut self, event: &Event) -> Result<bool, String> {
+        match event {
+            Event::KeyDown {
+                keycode: Some(Keycode::F),
+                repeat: false,
+                ..
+            } => {
+                self.set_filter(self.filter.next())?;
+                println!("Filter {}", self.filter);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F10),
+                repeat: false,
+                ..
+            } => {
+                self.mode = self.mode.next();
+                println!("Scale mode {}", self.mode);
             }
+            Event::KeyDown {
+                keycode: Some(Keycode::F11),
+                repeat: false,
+                ..
+            } => {
+                let fullscreen = self.canvas.window().fullscreen_state() == FullscreenType::Off;
+                self.set_fullscreen(fullscreen)?;
+            }
+            _ => return Ok(false),
         }
-        Ok(())
+        Ok(true)
     }
 
-    pub fn draw(&mut self, cpu: &mut CPU) -> Result<(), String> {
-        self.draw_background(cpu);
-        self.draw_foreground(cpu)?;
+    pub fn draw(&mut self, cpu: &Machine) -> Result<(), String> {
+        match self.filter {
+            Filter::Nearest | Filter::Bilinear => self
+                .texture
+                .with_lock(None, |buffer, pitch| frame::write_rgba(cpu, buffer, pitch))?,
+            filter => {
+                let rgb = filter.apply(&frame::to_rgb(cpu), GRID_X_SIZE, GRID_Y_SIZE);
+                let pitch = (GRID_X_SIZE * filter.scale() * 3) as usize;
+                self.texture
+                    .update(None, &rgb, pitch)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
+        let (width, height) = self.canvas.output_size()?;
+        let viewport = viewport(self.mode, width, height);
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        self.canvas.copy(
+            &self.texture,
+            None,
+            Rect::new(
+                viewport.x as i32,
+                viewport.y as i32,
+                viewport.width,
+                viewport.height,
+            ),
+        )?;
         self.canvas.present();
 
         Ok(())
     }
-
-    fn get_draw_color(&mut self, cpu: &mut CPU, palette_index: u8) -> Color {
-        let color = cpu.palette()[palette_index as usize];
-        return Color::RGB(
-            (color >> 16) as u8,
-            ((color >> 8) & 0xFF) as u8,
-            (color & 0xFF) as u8,
-        );
-    }
-
-    fn draw_background(&mut self, cpu: &mut CPU) {
-        let bgc_index = cpu.bgc();
-        let draw_color = self.get_draw_color(cpu, bgc_index);
-        self.canvas.set_draw_color(draw_color);
-        self.canvas.clear();
-    }
 }
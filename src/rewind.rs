@@ -0,0 +1,165 @@
+use std::collections::VecDeque;
+
+use crate::state::State;
+
+/// A ring buffer of recent frames for rewinding. Only the newest state is
+/// kept whole; each older frame is stored as the run-length encoded XOR
+/// between it and the frame after it, which is mostly zeros.
+pub struct Rewind {
+    capacity: usize,
+    current: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Creates a buffer that can step back `capacity` frames.
+    pub fn new(capacity: usize) -> Rewind {
+        return Rewind {
+            capacity,
+            current: None,
+            deltas: VecDeque::with_capacity(capacity),
+        };
+    }
+
+    /// How many frames can currently be stepped back.
+    pub fn len(&self) -> usize {
+        return self.deltas.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.deltas.is_empty();
+    }
+
+    /// Total bytes held by the buffer.
+    pub fn size(&self) -> usize {
+        let current = self.current.as_ref().map_or(0, |current| current.len());
+        return current + self.deltas.iter().map(|delta| delta.len()).sum::<usize>();
+    }
+
+    pub fn clear(&mut self) {
+        self.current = None;
+        self.deltas.clear();
+    }
+
+    /// Records the state at the end of a frame.
+    pub fn push(&mut self, state: &State) {
+        if self.capacity == 0 {
+            return;
+        }
+        let next = state.to_bytes();
+        if let Some(current) = self.current.take() {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(encode_delta(&next, &current));
+        }
+        self.current = Some(next);
+    }
+
+    /// Steps back one frame, returning the state recorded before the newest
+    /// one, or None when there's nothing left to rewind.
+    pub fn pop(&mut self) -> Option<State> {
+        let delta = self.deltas.pop_back()?;
+        let current = self.current.as_mut()?;
+        apply_delta(current, &delta);
+        return State::from_bytes(current).ok();
+    }
+}
+
+/// Encodes `to` relative to `from` as runs of (unchanged bytes, changed
+/// bytes XORed with `from`). Each run starts with both lengths as u32 LE.
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = vec![];
+    let len = from.len().max(to.len());
+    let byte = |bytes: &[u8], index: usize| *bytes.get(index).unwrap_or(&0);
+    delta.extend_from_slice(&(to.len() as u32).to_le_bytes());
+
+    let mut index = 0;
+    while index < len {
+        let start = index;
+        while index < len && byte(from, index) == byte(to, index) {
+            index += 1;
+        }
+        let same = index - start;
+
+        let changed_start = index;
+        while index < len && byte(from, index) != byte(to, index) {
+            index += 1;
+        }
+        delta.extend_from_slice(&(same as u32).to_le_bytes());
+        delta.extend_from_slice(&((index - changed_start) as u32).to_le_bytes());
+        for i in changed_start..index {
+            delta.push(byte(from, i) ^ byte(to, i));
+        }
+    }
+    return delta;
+}
+
+fn apply_delta(bytes: &mut Vec<u8>, delta: &[u8]) {
+    let word = |offset: usize| {
+        u32::from_le_bytes([
+            delta[offset],
+            delta[offset + 1],
+            delta[offset + 2],
+            delta[offset + 3],
+        ]) as usize
+    };
+    let len = word(0);
+    bytes.resize(bytes.len().max(len), 0);
+
+    let mut offset = 4;
+    let mut index = 0;
+    while offset < delta.len() {
+        index += word(offset);
+        let changed = word(offset + 4);
+        offset += 8;
+        for byte in &mut bytes[index..index + changed] {
+            *byte ^= delta[offset];
+            offset += 1;
+        }
+        index += changed;
+    }
+    bytes.truncate(len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Machine;
+
+    #[test]
+    fn steps_back_through_frames() {
+        // Count r0 up once a frame: ADDI r0, 1; VBLNK; JMP 0x0000
+        let mut machine = Machine::with_program(&[
+            0x40, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+        ]);
+        let mut rewind = Rewind::new(3);
+
+        let mut states = vec![];
+        for _ in 0..5 {
+            machine.run_frame().unwrap();
+            rewind.push(&machine.state());
+            states.push(machine.state());
+        }
+        assert_eq!(rewind.len(), 3);
+        assert!(rewind.size() < 2 * states[0].to_bytes().len());
+
+        for expected in states[1..4].iter().rev() {
+            let state = rewind.pop().unwrap();
+            assert!(state == *expected);
+        }
+        assert!(rewind.pop().is_none());
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let from = vec![1, 2, 3, 4, 5, 6];
+        let to = vec![1, 9, 3, 4, 7, 6, 8];
+        let mut bytes = from.clone();
+        apply_delta(&mut bytes, &encode_delta(&from, &to));
+        assert_eq!(bytes, to);
+        let mut bytes = to.clone();
+        apply_delta(&mut bytes, &encode_delta(&to, &from));
+        assert_eq!(bytes, from);
+    }
+}
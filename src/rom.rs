@@ -0,0 +1,267 @@
+use std::fmt;
+use std::path::Path;
+
+use binrw::binread;
+use binrw::io::Cursor;
+use binrw::BinReaderExt;
+
+use crate::cpu::MEM_SIZE;
+
+pub const HEADER_SIZE: usize = 16;
+
+#[binread]
+#[br(little, magic = b"CH16")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomHeader {
+    #[br(temp)]
+    reserved: u8,
+    /// Specification version as (major, minor)
+    #[br(map = |version: u8| (version >> 4, version & 0xF))]
+    pub spec: (u8, u8),
+    /// Size of the program following the header
+    pub size: u32,
+    /// Initial value of PC
+    pub start: u16,
+    /// CRC-32 of the program
+    pub crc: u32,
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(std::io::Error),
+    /// The file starts with the CH16 magic but is too short to hold a header.
+    TruncatedHeader,
+    /// The header's size field doesn't match the payload that follows it.
+    SizeMismatch {
+        header: u32,
+        actual: usize,
+    },
+    /// The CRC-32 of the payload doesn't match the header.
+    CrcMismatch {
+        header: u32,
+        actual: u32,
+    },
+    /// The payload doesn't fit in memory.
+    TooLarge(usize),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(e) => write!(f, "Failed to read ROM: {}", e),
+            RomError::TruncatedHeader => write!(f, "ROM header is truncated"),
+            RomError::SizeMismatch { header, actual } => write!(
+                f,
+                "ROM header declares {} bytes but the ROM has {}",
+                header, actual
+            ),
+            RomError::CrcMismatch { header, actual } => write!(
+                f,
+                "ROM CRC-32 is {:#010X} but the header declares {:#010X}",
+                actual, header
+            ),
+            RomError::TooLarge(size) => write!(
+                f,
+                "ROM is {} bytes, larger than the {} bytes of memory",
+                size, MEM_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+impl From<std::io::Error> for RomError {
+    fn from(e: std::io::Error) -> RomError {
+        return RomError::Io(e);
+    }
+}
+
+/// Parses a specification version written as `major.minor`, e.g. `1.3`.
+pub fn parse_spec_version(version: &str) -> Result<(u8, u8), String> {
+    let parse = |part: Option<&str>| part.and_then(|part| part.parse::<u8>().ok());
+    let mut parts = version.splitn(2, '.');
+    return match (parse(parts.next()), parse(parts.next())) {
+        (Some(major), Some(minor)) if major < 16 && minor < 16 => Ok((major, minor)),
+        _ => Err(format!("{} is not a spec version like 1.3", version)),
+    };
+}
+
+/// A Chip16 program, either a raw binary or one with a CH16 header.
+pub struct Rom {
+    header: Option<RomHeader>,
+    data: Vec<u8>,
+}
+
+impl Rom {
+    pub fn load(path: &Path, check_crc: bool) -> Result<Rom, RomError> {
+        return Rom::from_bytes(std::fs::read(path)?, check_crc);
+    }
+
+    pub fn from_bytes(mut bytes: Vec<u8>, check_crc: bool) -> Result<Rom, RomError> {
+        if !bytes.starts_with(b"CH16") {
+            if bytes.len() > MEM_SIZE {
+                return Err(RomError::TooLarge(bytes.len()));
+            }
+            return Ok(Rom {
+                header: None,
+                data: bytes,
+            });
+        }
+
+        if bytes.len() < HEADER_SIZE {
+            return Err(RomError::TruncatedHeader);
+        }
+
+        let data = bytes.split_off(HEADER_SIZE);
+        let header: RomHeader = Cursor::new(bytes)
+            .read_le()
+            .map_err(|_| RomError::TruncatedHeader)?;
+
+        if header.size as usize != data.len() {
+            return Err(RomError::SizeMismatch {
+                header: header.size,
+                actual: data.len(),
+            });
+        }
+        if data.len() > MEM_SIZE {
+            return Err(RomError::TooLarge(data.len()));
+        }
+
+        let crc = crc32fast::hash(&data);
+        if check_crc && crc != header.crc {
+            return Err(RomError::CrcMismatch {
+                header: header.crc,
+                actual: crc,
+            });
+        }
+
+        return Ok(Rom {
+            header: Some(header),
+            data,
+        });
+    }
+
+    /// Builds a headered ROM around `data`, computing its size and CRC-32.
+    pub fn with_header(spec: (u8, u8), start: u16, data: Vec<u8>) -> Rom {
+        let header = RomHeader {
+            spec,
+            size: data.len() as u32,
+            start,
+            crc: crc32fast::hash(&data),
+        };
+        return Rom {
+            header: Some(header),
+            data,
+        };
+    }
+
+    /// The ROM as it would be stored on disk, header included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        if let Some(header) = &self.header {
+            bytes.extend_from_slice(b"CH16");
+            bytes.push(0);
+            bytes.push(header.spec.0 << 4 | header.spec.1);
+            bytes.extend_from_slice(&header.size.to_le_bytes());
+            bytes.extend_from_slice(&header.start.to_le_bytes());
+            bytes.extend_from_slice(&header.crc.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.data);
+        return bytes;
+    }
+
+    /// The program bytes, without the header.
+    pub fn data(&self) -> &[u8] {
+        return &self.data;
+    }
+
+    pub fn header(&self) -> Option<&RomHeader> {
+        return self.header.as_ref();
+    }
+
+    /// The (major, minor) specification version the ROM was built for, if it
+    /// has a header.
+    pub fn spec_version(&self) -> Option<(u8, u8)> {
+        return self.header.map(|header| header.spec);
+    }
+
+    /// Where execution starts: the header's start address, or 0x0000.
+    pub fn start(&self) -> u16 {
+        return self.header.map_or(0x0000, |header| header.start);
+    }
+
+    /// The initial contents of memory with the program loaded at 0x0000.
+    pub fn mem(&self) -> [u8; MEM_SIZE] {
+        let mut mem = [0; MEM_SIZE];
+        mem[..self.data.len()].copy_from_slice(&self.data);
+        return mem;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headered(spec: u8, start: u16, data: &[u8]) -> Vec<u8> {
+        let mut rom = b"CH16".to_vec();
+        rom.push(0);
+        rom.push(spec);
+        rom.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        rom.extend_from_slice(&start.to_le_bytes());
+        rom.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        rom.extend_from_slice(data);
+        return rom;
+    }
+
+    #[test]
+    fn parses_header() {
+        let rom = Rom::from_bytes(headered(0x13, 0x0244, &[1, 2, 3, 4]), true).unwrap();
+        assert_eq!(
+            rom.header(),
+            Some(&RomHeader {
+                spec: (1, 3),
+                size: 4,
+                start: 0x0244,
+                crc: crc32fast::hash(&[1, 2, 3, 4]),
+            })
+        );
+        assert_eq!(rom.start(), 0x0244);
+        assert_eq!(rom.data(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn loads_raw_rom() {
+        let rom = Rom::from_bytes(vec![0x20, 0x00, 0x34, 0x12], true).unwrap();
+        assert!(rom.header().is_none());
+        assert_eq!(rom.spec_version(), None);
+        assert_eq!(rom.start(), 0x0000);
+        assert_eq!(rom.data(), &[0x20, 0x00, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(
+            Rom::from_bytes(b"CH16\0\x11".to_vec(), true),
+            Err(RomError::TruncatedHeader)
+        ));
+
+        let mut rom = headered(0x11, 0, &[1, 2, 3, 4]);
+        rom.push(5);
+        assert!(matches!(
+            Rom::from_bytes(rom, true),
+            Err(RomError::SizeMismatch {
+                header: 4,
+                actual: 5
+            })
+        ));
+
+        let mut rom = headered(0x11, 0, &[1, 2, 3, 4]);
+        rom[HEADER_SIZE] = 0xFF;
+        assert!(matches!(
+            Rom::from_bytes(rom.clone(), true),
+            Err(RomError::CrcMismatch { .. })
+        ));
+        assert!(Rom::from_bytes(rom, false).is_ok());
+    }
+}
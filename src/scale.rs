@@ -0,0 +1,116 @@
+use std::fmt;
+
+use crate::{GRID_X_SIZE, GRID_Y_SIZE};
+
+/// How the screen is scaled to the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    /// The largest whole multiple that fits, so every pixel is the same size.
+    Integer,
+    /// As large as fits while keeping the aspect ratio.
+    Fit,
+    /// Fills the window, distorting the aspect ratio.
+    Stretch,
+}
+
+impl ScaleMode {
+    pub fn parse(mode: &str) -> Result<ScaleMode, String> {
+        return match mode.trim().to_ascii_lowercase().as_str() {
+            "integer" => Ok(ScaleMode::Integer),
+            "fit" => Ok(ScaleMode::Fit),
+            "stretch" => Ok(ScaleMode::Stretch),
+            _ => Err(format!("{} isn't integer, fit or stretch", mode)),
+        };
+    }
+
+    /// The mode after this one, for cycling through them with a hotkey.
+    pub fn next(self) -> ScaleMode {
+        return match self {
+            ScaleMode::Integer => ScaleMode::Fit,
+            ScaleMode::Fit => ScaleMode::Stretch,
+            ScaleMode::Stretch => ScaleMode::Integer,
+        };
+    }
+}
+
+impl fmt::Display for ScaleMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "{}",
+            match self {
+                ScaleMode::Integer => "integer",
+                ScaleMode::Fit => "fit",
+                ScaleMode::Stretch => "stretch",
+            }
+        );
+    }
+}
+
+/// The part of a window the screen is drawn to. The rest is letterboxed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Centers the screen in a window of the given size. Integer scaling falls
+/// back to fitting when the window is smaller than the screen.
+pub fn viewport(mode: ScaleMode, window_width: u32, window_height: u32) -> Viewport {
+    let scale = (window_width / GRID_X_SIZE).min(window_height / GRID_Y_SIZE);
+    let (width, height) = match mode {
+        ScaleMode::Integer if scale > 0 => (GRID_X_SIZE * scale, GRID_Y_SIZE * scale),
+        ScaleMode::Stretch => (window_width, window_height),
+        _ if window_width * GRID_Y_SIZE <= window_height * GRID_X_SIZE => {
+            (window_width, window_width * GRID_Y_SIZE / GRID_X_SIZE)
+        }
+        _ => (window_height * GRID_X_SIZE / GRID_Y_SIZE, window_height),
+    };
+    return Viewport {
+        x: (window_width - width) / 2,
+        y: (window_height - height) / 2,
+        width,
+        height,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Viewport {
+        return Viewport {
+            x,
+            y,
+            width,
+            height,
+        };
+    }
+
+    #[test]
+    fn letterboxes_each_mode() {
+        assert_eq!(viewport(ScaleMode::Integer, 640, 480), rect(0, 0, 640, 480));
+        assert_eq!(
+            viewport(ScaleMode::Integer, 1920, 1080),
+            rect(320, 60, 1280, 960)
+        );
+        assert_eq!(viewport(ScaleMode::Integer, 300, 240), rect(0, 7, 300, 225));
+
+        assert_eq!(
+            viewport(ScaleMode::Fit, 1920, 1080),
+            rect(240, 0, 1440, 1080)
+        );
+        assert_eq!(viewport(ScaleMode::Fit, 800, 1000), rect(0, 200, 800, 600));
+
+        assert_eq!(
+            viewport(ScaleMode::Stretch, 1920, 1080),
+            rect(0, 0, 1920, 1080)
+        );
+
+        assert_eq!(ScaleMode::parse("Fit"), Ok(ScaleMode::Fit));
+        assert!(ScaleMode::parse("zoom").is_err());
+        assert_eq!(ScaleMode::Stretch.next(), ScaleMode::Integer);
+    }
+}
@@ -0,0 +1,100 @@
+pub enum WaveForm {
+    Triangle = 0,
+    Sawtooth,
+    Square,
+    Noise,
+}
+
+pub fn wave_form_from_num(index: u8) -> Result<WaveForm, String> {
+    return match index {
+        0 => Ok(WaveForm::Triangle),
+        1 => Ok(WaveForm::Sawtooth),
+        2 => Ok(WaveForm::Square),
+        3 => Ok(WaveForm::Noise),
+        _ => Err(String::from("Invalid wave type index")),
+    };
+}
+
+/// The sound being played, as captured in save states.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SoundState {
+    pub frequency: u16,
+    pub duration: u16,
+    /// The last SNG parameters: attack, decay, sustain, release, volume and
+    /// wave type.
+    pub params: [u8; 6],
+    /// Whether the sound uses the SNG envelope rather than a plain tone.
+    pub custom: bool,
+    pub playing: bool,
+    /// How far playback has got through the envelope, in samples.
+    pub position: u32,
+}
+
+/// Sound generator driven by the SND0-3, SNP and SNG instructions. Frontends
+/// implement this on top of whatever audio output they have.
+pub trait Sound {
+    fn play_sound(&mut self, frequency: u16, duration: u16);
+
+    fn play_custom_sound(&mut self, frequency: u16, duration: u16);
+
+    fn set_params(
+        &mut self,
+        attack: u8,
+        decay: u8,
+        sustain: u8,
+        release: u8,
+        volume: u8,
+        wave_type: u8,
+    ) -> Result<(), String>;
+
+    fn is_finished(&mut self) -> bool;
+
+    fn clear(&mut self);
+
+    fn start(&mut self);
+
+    /// Called at the end of every frame. Sounds are timed in frames so they
+    /// last the same emulated time however fast emulation is running.
+    fn frame(&mut self) {}
+
+    /// How fast emulation is running relative to real time, so output can be
+    /// resampled to match, or None to mute it when paused or uncapped.
+    fn set_speed(&mut self, _speed: Option<f64>) {}
+
+    fn state(&mut self) -> SoundState {
+        return SoundState::default();
+    }
+
+    /// Resumes playback from a saved state.
+    fn restore(&mut self, _state: &SoundState) {}
+}
+
+/// Silent sound generator for running without an audio device.
+pub struct NullSound;
+
+impl Sound for NullSound {
+    fn play_sound(&mut self, _frequency: u16, _duration: u16) {}
+
+    fn play_custom_sound(&mut self, _frequency: u16, _duration: u16) {}
+
+    fn set_params(
+        &mut self,
+        _attack: u8,
+        _decay: u8,
+        _sustain: u8,
+        _release: u8,
+        _volume: u8,
+        wave_type: u8,
+    ) -> Result<(), String> {
+        wave_form_from_num(wave_type)?;
+        Ok(())
+    }
+
+    fn is_finished(&mut self) -> bool {
+        return false;
+    }
+
+    fn clear(&mut self) {}
+
+    fn start(&mut self) {}
+}
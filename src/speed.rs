@@ -0,0 +1,89 @@
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+/// Slow motion speeds stepped through with the minus key.
+const SLOW_MOTION: [f64; 3] = [1.0, 0.5, 0.25];
+
+/// Runtime speed controls: Tab held fast-forwards, minus steps through slow
+/// motion, P pauses and N advances a single frame while paused.
+pub struct Speed {
+    /// Fast-forward multiplier, uncapped if None.
+    fast_forward: Option<u32>,
+    fast_forwarding: bool,
+    slow_motion: usize,
+    paused: bool,
+    advance: bool,
+}
+
+impl Speed {
+    pub fn new(fast_forward: Option<u32>) -> Speed {
+        return Speed {
+            fast_forward,
+            fast_forwarding: false,
+            slow_motion: 0,
+            paused: false,
+            advance: false,
+        };
+    }
+
+    /// Handles the speed hotkeys, returning true if the event was one.
+    pub fn handle(&mut self, event: &Event) -> bool {
+        match event {
+            Event::KeyDown {
+                keycode: Some(Keycode::Tab),
+                ..
+            } => self.fast_forwarding = true,
+            Event::KeyUp {
+                keycode: Some(Keycode::Tab),
+                ..
+            } => self.fast_forwarding = false,
+            Event::KeyDown {
+                keycode: Some(Keycode::Minus),
+                repeat: false,
+                ..
+            } => {
+                self.slow_motion = (self.slow_motion + 1) % SLOW_MOTION.len();
+                println!("Speed {}x", SLOW_MOTION[self.slow_motion]);
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::P),
+                repeat: false,
+                ..
+            } => {
+                self.paused = !self.paused;
+                println!("{}", if self.paused { "Paused" } else { "Resumed" });
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::N),
+                ..
+            } if self.paused => self.advance = true,
+            _ => return false,
+        }
+        return true;
+    }
+
+    /// Whether to run the next frame, which is only once per frame-advance
+    /// while paused.
+    pub fn should_run(&mut self) -> bool {
+        let advance = self.advance;
+        self.advance = false;
+        return !self.paused || advance;
+    }
+
+    /// How fast frames should be run relative to real time, None when
+    /// uncapped.
+    pub fn frame_speed(&self) -> Option<f64> {
+        if self.fast_forwarding && !self.paused {
+            return self.fast_forward.map(|speed| speed as f64);
+        }
+        return Some(SLOW_MOTION[self.slow_motion]);
+    }
+
+    /// The speed to play sound at, muting it while paused or uncapped.
+    pub fn sound_speed(&self) -> Option<f64> {
+        if self.paused {
+            return None;
+        }
+        return self.frame_speed();
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::path::Path;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use crate::cpu::{MEM_SIZE, SCREEN_BUF_SIZE};
+use crate::sound::SoundState;
+
+const MAGIC: &[u8; 4] = b"C16S";
+/// Bumped whenever the layout or meaning of the fields below changes. Older
+/// versions aren't loaded.
+pub const VERSION: u16 = 3;
+
+/// A snapshot of everything a running program can observe, used for save
+/// states.
+#[derive(Clone, PartialEq, Eq)]
+pub struct State {
+    pub registers: [i16; 16],
+    pub pc: u16,
+    pub sp: u16,
+    /// Flags packed as in PUSHF: C, Z, O and N in bits 1, 2, 6 and 7.
+    pub flags: u8,
+    pub vblnk: bool,
+    /// The spec revision the machine runs as, and whether it lets newer
+    /// opcodes through, so a state resumes with the opcodes it was saved with.
+    pub spec: (u8, u8),
+    pub allow_newer_opcodes: bool,
+    /// Cycles run since the start of the current second.
+    pub cycles: u32,
+    pub bg: u8,
+    pub spritew: u8,
+    pub spriteh: u8,
+    pub hflip: bool,
+    pub vflip: bool,
+    pub palette: [u32; 16],
+    pub controls: [u8; 2],
+    pub rng_seed: [u8; 32],
+    pub rng_stream: u64,
+    pub rng_word_pos: u128,
+    /// Where the stack starts, checked for overflows.
+    pub stack_base: u16,
+    /// Where the return addresses of the subroutines being executed are
+    /// stored on the stack, for the debugger's call stack.
+    pub stack: Vec<u16>,
+    pub sound: SoundState,
+    pub mem: Box<[u8; MEM_SIZE]>,
+    pub screen: Box<[u8; SCREEN_BUF_SIZE]>,
+}
+
+impl State {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MEM_SIZE + SCREEN_BUF_SIZE + 512);
+        out.extend_from_slice(MAGIC);
+        out.write_u16::<LE>(VERSION).unwrap();
+
+        for register in self.registers {
+            out.write_i16::<LE>(register).unwrap();
+        }
+        out.write_u16::<LE>(self.pc).unwrap();
+        out.write_u16::<LE>(self.sp).unwrap();
+        out.push(self.flags);
+        out.push(self.vblnk as u8);
+        out.extend_from_slice(&[self.spec.0, self.spec.1]);
+        out.push(self.allow_newer_opcodes as u8);
+        out.write_u32::<LE>(self.cycles).unwrap();
+
+        out.extend_from_slice(&[self.bg, self.spritew, self.spriteh]);
+        out.extend_from_slice(&[self.hflip as u8, self.vflip as u8]);
+        for color in self.palette {
+            out.write_u32::<LE>(color).unwrap();
+        }
+        out.extend_from_slice(&self.controls);
+
+        out.extend_from_slice(&self.rng_seed);
+        out.write_u64::<LE>(self.rng_stream).unwrap();
+        out.write_u128::<LE>(self.rng_word_pos).unwrap();
+
+        out.write_u16::<LE>(self.stack_base).unwrap();
+        out.write_u16::<LE>(self.stack.len() as u16).unwrap();
+        for addr in &self.stack {
+            out.write_u16::<LE>(*addr).unwrap();
+        }
+
+        let sound = &self.sound;
+        out.write_u16::<LE>(sound.frequency).unwrap();
+        out.write_u16::<LE>(sound.duration).unwrap();
+        out.extend_from_slice(&sound.params);
+        out.extend_from_slice(&[sound.custom as u8, sound.playing as u8]);
+        out.write_u32::<LE>(sound.position).unwrap();
+
+        out.extend_from_slice(&self.mem[..]);
+        out.extend_from_slice(&self.screen[..]);
+        return out;
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        return std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e));
+    }
+
+    pub fn load(path: &Path) -> Result<State, String> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        return State::from_bytes(&bytes);
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<State, String> {
+        return read_state(&mut Cursor::new(bytes)).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => String::from("Save state is truncated"),
+            _ => e.to_string(),
+        });
+    }
+}
+
+fn read_state(input: &mut Cursor<&[u8]>) -> std::io::Result<State> {
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Not a Chip16 save state",
+        ));
+    }
+    let version = input.read_u16::<LE>()?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Save state is version {}, this build reads version {}",
+                version, VERSION
+            ),
+        ));
+    }
+
+    let mut registers = [0; 16];
+    input.read_i16_into::<LE>(&mut registers)?;
+    let pc = input.read_u16::<LE>()?;
+    let sp = input.read_u16::<LE>()?;
+    let flags = input.read_u8()?;
+    let vblnk = input.read_u8()? != 0;
+    let spec = (input.read_u8()?, input.read_u8()?);
+    let allow_newer_opcodes = input.read_u8()? != 0;
+    let cycles = input.read_u32::<LE>()?;
+
+    let bg = input.read_u8()?;
+    let spritew = input.read_u8()?;
+    let spriteh = input.read_u8()?;
+    let hflip = input.read_u8()? != 0;
+    let vflip = input.read_u8()? != 0;
+    let mut palette = [0; 16];
+    input.read_u32_into::<LE>(&mut palette)?;
+    let mut controls = [0; 2];
+    input.read_exact(&mut controls)?;
+
+    let mut rng_seed = [0; 32];
+    input.read_exact(&mut rng_seed)?;
+    let rng_stream = input.read_u64::<LE>()?;
+    let rng_word_pos = input.read_u128::<LE>()?;
+
+    let stack_base = input.read_u16::<LE>()?;
+    let mut stack = vec![0; input.read_u16::<LE>()? as usize];
+    input.read_u16_into::<LE>(&mut stack)?;
+
+    let frequency = input.read_u16::<LE>()?;
+    let duration = input.read_u16::<LE>()?;
+    let mut params = [0; 6];
+    input.read_exact(&mut params)?;
+    let custom = input.read_u8()? != 0;
+    let playing = input.read_u8()? != 0;
+    let position = input.read_u32::<LE>()?;
+
+    let mut mem = Box::new([0; MEM_SIZE]);
+    input.read_exact(&mut mem[..])?;
+    let mut screen = Box::new([0; SCREEN_BUF_SIZE]);
+    input.read_exact(&mut screen[..])?;
+
+    return Ok(State {
+        registers,
+        pc,
+        sp,
+        flags,
+        vblnk,
+        spec,
+        allow_newer_opcodes,
+        cycles,
+        bg,
+        spritew,
+        spriteh,
+        hflip,
+        vflip,
+        palette,
+        controls,
+        rng_seed,
+        rng_stream,
+        rng_word_pos,
+        stack_base,
+        stack,
+        sound: SoundState {
+            frequency,
+            duration,
+            params,
+            custom,
+            playing,
+            position,
+        },
+        mem,
+        screen,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Machine;
+
+    #[test]
+    fn restores_a_running_machine() {
+        let mut program = vec![
+            0x20, 0x00, 0x05, 0x00, // ldi r0, 5
+            0x03, 0x00, 0x03, 0x00, // bgc 3
+            0x04, 0x00, 0x04, 0x02, // spr 0x0204
+            0x08, 0x00, 0x00, 0x03, // flip 1, 1
+            0x05, 0x10, 0x00, 0x02, // drw r0, r1, 0x0200
+            0x30, 0x00, 0x00, 0x10, // stm r0, 0x1000
+            0xC0, 0x00, 0x00, 0x00, // push r0
+            0x14, 0x00, 0x24, 0x00, // call 0x0024
+            0x10, 0x00, 0x20, 0x00, // jmp 0x0020
+            0x50, 0x00, 0x05, 0x00, // subi r0, 5
+            0x02, 0x00, 0x00, 0x00, // vblnk
+            0x15, 0x00, 0x00, 0x00, // ret
+        ];
+        program.resize(0x0200, 0);
+        program.extend_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+        let mut machine = Machine::with_program(&program);
+        machine.set_spec((1, 1));
+        machine.set_allow_newer_opcodes(true);
+        machine.seed_rng(7);
+        machine.set_controller(0, 0b0000_0101);
+        machine.run_frame().unwrap();
+        let bytes = machine.state().to_bytes();
+
+        let mut restored = Machine::with_program(&[]);
+        restored.restore(&State::from_bytes(&bytes).unwrap());
+        assert_eq!(restored.registers(), machine.registers());
+        assert_eq!(restored.pc(), 0x002C);
+        assert_eq!(restored.sp(), machine.sp());
+        assert!(restored.flags().Z);
+        assert_eq!(restored.mem()[0x1000], 5);
+        assert_eq!(restored.call_stack(), machine.call_stack());
+        assert_eq!(restored.timing().cycles(), machine.timing().cycles());
+        assert_eq!(restored.graphics().bg, 3);
+        assert!(restored.graphics().hflip && restored.graphics().vflip);
+        assert_eq!(restored.screen()[..], machine.screen()[..]);
+        assert_eq!(restored.controllers(), machine.controllers());
+        assert_eq!(restored.spec(), (1, 1));
+        assert!(restored.allow_newer_opcodes());
+        assert!(restored.state() == machine.state());
+
+        // Both carry on the same way, RNG included
+        machine.run_frame().unwrap();
+        restored.run_frame().unwrap();
+        assert!(restored.state() == machine.state());
+    }
+
+    #[test]
+    fn rejects_other_files_and_versions() {
+        let mut machine = Machine::with_program(&[]);
+        let mut bytes = machine.state().to_bytes();
+
+        assert!(State::from_bytes(&bytes).is_ok());
+        assert_eq!(
+            State::from_bytes(&bytes[..100]).err().unwrap(),
+            "Save state is truncated"
+        );
+        bytes[4] = 0xFF;
+        assert_eq!(
+            State::from_bytes(&bytes).err().unwrap(),
+            "Save state is version 255, this build reads version 3"
+        );
+        assert_eq!(
+            State::from_bytes(b"CH16\x00\x11").err().unwrap(),
+            "Not a Chip16 save state"
+        );
+    }
+}
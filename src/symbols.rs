@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Names for addresses, such as the labels of an assembled program.
+///
+/// Symbol files have a hex address and a name on each line, with `;`
+/// starting a comment:
+///
+/// `0244 draw_player`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    /// Names `addr`, keeping the first name given to an address.
+    pub fn insert(&mut self, addr: u16, name: &str) {
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.names.is_empty();
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&str> {
+        return self.names.get(&addr).map(String::as_str);
+    }
+
+    /// Describes an address relative to the closest symbol at or before it,
+    /// such as `draw_player+0x8`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let (start, name) = self.names.range(..=addr).next_back()?;
+        return Some(match addr - start {
+            0 => name.clone(),
+            offset => format!("{}+{:#X}", name, offset),
+        });
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let (addr, name) = match (words.next(), words.next(), words.next()) {
+                (None, ..) => continue,
+                (Some(addr), Some(name), None) => (addr, name),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected an address and a name",
+                        index + 1
+                    ))
+                }
+            };
+            let hex = addr
+                .strip_prefix("0x")
+                .or_else(|| addr.strip_prefix("0X"))
+                .unwrap_or(addr);
+            let addr = u16::from_str_radix(hex, 16)
+                .map_err(|_| format!("line {}: {} isn't a hex address", index + 1, addr))?;
+            symbols.insert(addr, name);
+        }
+        return Ok(symbols);
+    }
+
+    pub fn load(path: &Path) -> Result<Symbols, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        return Symbols::parse(&text).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    pub fn to_text(&self) -> String {
+        return self
+            .names
+            .iter()
+            .map(|(addr, name)| format!("{:04X} {}\n", addr, name))
+            .collect();
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        return std::fs::write(path, self.to_text())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_describes_addresses() {
+        let symbols = Symbols::parse("; labels\n0000 start\n0x0244 draw ; sprite\n\n").unwrap();
+        assert_eq!(symbols.get(0x0244), Some("draw"));
+        assert_eq!(symbols.describe(0x0244).as_deref(), Some("draw"));
+        assert_eq!(symbols.describe(0x024C).as_deref(), Some("draw+0x8"));
+        assert_eq!(symbols.describe(0x0010).as_deref(), Some("start+0x10"));
+        assert_eq!(Symbols::parse(&symbols.to_text()), Ok(symbols));
+
+        assert!(Symbols::parse("0244").is_err());
+        assert!(Symbols::parse("zz draw").is_err());
+        assert_eq!(Symbols::default().describe(0x0244), None);
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+
+use crate::{CLOCK_RATE, FPS};
+
+/// Frames end at VBLNK when the clock is unlimited, or after this many cycles
+/// for programs that never wait for one.
+pub const UNLIMITED_FRAME_CYCLES: u32 = 64 * CLOCK_RATE / FPS;
+
+/// The speed the CPU runs at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Hz(u32),
+    Unlimited,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        return Clock::Hz(CLOCK_RATE);
+    }
+}
+
+impl Clock {
+    /// Parses a clock speed such as `1mhz`, `2MHz`, `500khz`, `1000000` or
+    /// `unlimited`.
+    pub fn parse(clock: &str) -> Result<Clock, String> {
+        let lower = clock.trim().to_ascii_lowercase();
+        if lower == "unlimited" {
+            return Ok(Clock::Unlimited);
+        }
+
+        let (number, scale) = if let Some(number) = lower.strip_suffix("mhz") {
+            (number, 1_000_000.0)
+        } else if let Some(number) = lower.strip_suffix("khz") {
+            (number, 1_000.0)
+        } else {
+            (lower.strip_suffix("hz").unwrap_or(&lower), 1.0)
+        };
+        let hz = number
+            .trim()
+            .parse::<f64>()
+            .map(|number| (number * scale).round())
+            .map_err(|_| format!("{} isn't a clock speed like 1mhz or unlimited", clock))?;
+        if !(FPS as f64..=u32::MAX as f64).contains(&hz) {
+            return Err(format!(
+                "{} is out of range, the clock must be at least {}hz",
+                clock, FPS
+            ));
+        }
+        return Ok(Clock::Hz(hz as u32));
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Clock::Hz(hz) if hz % 1_000_000 == 0 => write!(f, "{}mhz", hz / 1_000_000),
+            Clock::Hz(hz) if hz % 1_000 == 0 => write!(f, "{}khz", hz / 1_000),
+            Clock::Hz(hz) => write!(f, "{}hz", hz),
+            Clock::Unlimited => write!(f, "unlimited"),
+        };
+    }
+}
+
+/// Counts the cycles taken by each instruction and decides where frames end.
+///
+/// With a 1 MHz clock a frame is 16666.67 cycles, so frames are 16666 or
+/// 16667 cycles long in a pattern that adds up to exactly one million every
+/// 60 frames rather than drifting.
+#[derive(Debug, Clone)]
+pub struct Timing {
+    clock: Clock,
+    /// Cycles taken by each opcode. The spec gives every instruction one.
+    costs: [u32; 0x100],
+    /// Cycles since the start of the current second, or of the current frame
+    /// when the clock is unlimited.
+    cycles: u32,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        return Timing::new(Clock::default());
+    }
+}
+
+impl Timing {
+    pub fn new(clock: Clock) -> Timing {
+        return Timing {
+            clock,
+            costs: [1; 0x100],
+            cycles: 0,
+        };
+    }
+
+    pub fn clock(&self) -> Clock {
+        return self.clock;
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.cycles = match clock {
+            Clock::Hz(hz) => self.cycles % hz,
+            Clock::Unlimited => self.cycles % UNLIMITED_FRAME_CYCLES,
+        };
+    }
+
+    pub fn cost(&self, opcode: u8) -> u32 {
+        return self.costs[usize::from(opcode)];
+    }
+
+    pub fn set_cost(&mut self, opcode: u8, cycles: u32) {
+        self.costs[usize::from(opcode)] = cycles;
+    }
+
+    pub fn cycles(&self) -> u32 {
+        return self.cycles;
+    }
+
+    pub fn set_cycles(&mut self, cycles: u32) {
+        self.cycles = cycles;
+        self.set_clock(self.clock);
+    }
+
+    /// The cycle count at which the current frame ends.
+    pub fn frame_end(&self) -> u32 {
+        return match self.clock {
+            Clock::Hz(hz) => {
+                let (hz, fps) = (u64::from(hz), u64::from(FPS));
+                let frame = u64::from(self.cycles) * fps / hz + 1;
+                (frame * hz).div_ceil(fps) as u32
+            }
+            Clock::Unlimited => UNLIMITED_FRAME_CYCLES,
+        };
+    }
+
+    /// Cycles left before the current frame ends.
+    pub fn remaining(&self) -> u32 {
+        return self.frame_end() - self.cycles;
+    }
+
+    /// Accounts for an executed instruction, returning true if it finished
+    /// the frame.
+    pub fn tick(&mut self, opcode: u8) -> bool {
+        return self.advance(self.cost(opcode));
+    }
+
+    /// Skips the rest of the current frame, as VBLNK waits for it to end.
+    pub fn finish_frame(&mut self) {
+        self.advance(self.remaining());
+    }
+
+    fn advance(&mut self, cycles: u32) -> bool {
+        let end = self.frame_end();
+        self.cycles += cycles;
+        if self.cycles < end {
+            return false;
+        }
+        let wrap = match self.clock {
+            Clock::Hz(hz) => hz,
+            Clock::Unlimited => end,
+        };
+        if self.cycles >= wrap {
+            self.cycles -= wrap;
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_clocks() {
+        assert_eq!(Clock::parse("1mhz"), Ok(Clock::Hz(1_000_000)));
+        assert_eq!(Clock::parse("2MHz"), Ok(Clock::Hz(2_000_000)));
+        assert_eq!(Clock::parse("1.5mhz"), Ok(Clock::Hz(1_500_000)));
+        assert_eq!(Clock::parse("500khz"), Ok(Clock::Hz(500_000)));
+        assert_eq!(Clock::parse("12345"), Ok(Clock::Hz(12_345)));
+        assert_eq!(Clock::parse("unlimited"), Ok(Clock::Unlimited));
+        assert!(Clock::parse("fast").is_err());
+        assert!(Clock::parse("1hz").is_err());
+        assert_eq!(Clock::Hz(2_000_000).to_string(), "2mhz");
+    }
+
+    #[test]
+    fn frames_add_up_to_the_clock() {
+        let mut timing = Timing::default();
+        let mut lengths = vec![];
+        for _ in 0..FPS * 2 {
+            let mut cycles = 0;
+            while !timing.tick(0x00) {
+                cycles += 1;
+            }
+            lengths.push(cycles + 1);
+        }
+        assert_eq!(&lengths[..3], [16667, 16667, 16666]);
+        assert_eq!(lengths[..60].iter().sum::<u32>(), CLOCK_RATE);
+        assert_eq!(lengths[..60], lengths[60..]);
+        assert_eq!(timing.cycles(), 0);
+
+        timing.tick(0x00);
+        timing.finish_frame();
+        assert_eq!(timing.cycles(), 16667);
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::disasm::disassemble;
+use crate::Machine;
+
+/// Where tracing starts or stops: at an address or at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Pc(u16),
+    Frame(u32),
+}
+
+impl Condition {
+    /// Parses `pc:0x0244` or `frame:120`.
+    pub fn parse(condition: &str) -> Result<Condition, String> {
+        let invalid = || format!("{} should look like pc:0x0244 or frame:120", condition);
+        let (kind, value) = condition.split_once(':').ok_or_else(invalid)?;
+        return match kind {
+            "pc" => {
+                let hex = value
+                    .strip_prefix("0x")
+                    .or_else(|| value.strip_prefix("0X"))
+                    .unwrap_or(value);
+                u16::from_str_radix(hex, 16)
+                    .map(Condition::Pc)
+                    .map_err(|_| invalid())
+            }
+            "frame" => value.parse().map(Condition::Frame).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        };
+    }
+
+    fn matches(&self, pc: u16, frame: u32) -> bool {
+        return match self {
+            Condition::Pc(addr) => *addr == pc,
+            Condition::Frame(start) => *start == frame,
+        };
+    }
+}
+
+/// The layout of trace lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `0244  20 00 34 12  ldi r0, 0x1234           R0:0001 ... RF:0000 C:0 Z:0 O:0 N:0 SP:FDF0`
+    Default,
+    /// mash16's CPU state dump, with no disassembly to differ between
+    /// emulators and the flags packed the way PUSHF stores them:
+    ///
+    /// `0244: 20 00 34 12 | r0=0001 ... rf=0000 | sp=fdf0 f=00`
+    Mash16,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format, String> {
+        return match format.trim().to_ascii_lowercase().as_str() {
+            "default" => Ok(Format::Default),
+            "mash16" => Ok(Format::Mash16),
+            _ => Err(format!(
+                "{} isn't a trace format, expected default or mash16",
+                format
+            )),
+        };
+    }
+}
+
+/// Writes a line for every instruction executed, showing the machine as it
+/// was just before the instruction ran. Columns are fixed width so traces
+/// from two runs, or two emulators, can be compared with diff.
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    start: Option<Condition>,
+    stop: Option<Condition>,
+    active: bool,
+    stopped: bool,
+    frame: u32,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    /// Traces from `start` until `stop`, or from the beginning and to the end
+    /// of the run if they aren't given.
+    pub fn new(
+        out: Box<dyn Write>,
+        format: Format,
+        start: Option<Condition>,
+        stop: Option<Condition>,
+    ) -> Tracer {
+        return Tracer {
+            out,
+            format,
+            active: start.is_none(),
+            start,
+            stop,
+            stopped: false,
+            frame: 0,
+            error: None,
+        };
+    }
+
+    pub fn create(
+        path: &Path,
+        format: Format,
+        start: Option<Condition>,
+        stop: Option<Condition>,
+    ) -> Result<Tracer, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        return Ok(Tracer::new(
+            Box::new(BufWriter::new(file)),
+            format,
+            start,
+            stop,
+        ));
+    }
+
+    /// Called by the machine before it executes an instruction.
+    pub fn trace(&mut self, machine: &Machine) {
+        if self.stopped {
+            return;
+        }
+        let pc = machine.pc();
+        if !self.active
+            && self
+                .start
+                .is_some_and(|start| start.matches(pc, self.frame))
+        {
+            self.active = true;
+        }
+        if self.active && self.stop.is_some_and(|stop| stop.matches(pc, self.frame)) {
+            self.active = false;
+            self.stopped = true;
+            return;
+        }
+        if self.active && self.error.is_none() {
+            let line = match self.format {
+                Format::Default => line(machine),
+                Format::Mash16 => mash16_line(machine),
+            };
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Called by the machine when it finishes a frame.
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Flushes the trace, reporting the first error writing it.
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(e) = self.error.take() {
+            return Err(format!("Failed to write trace: {}", e));
+        }
+        return self
+            .out
+            .flush()
+            .map_err(|e| format!("Failed to write trace: {}", e));
+    }
+}
+
+/// Formats the trace line for the instruction at PC.
+pub fn line(machine: &Machine) -> String {
+    let instruction = disassemble(machine.mem(), machine.pc());
+    let bytes = instruction.bytes;
+    let mut line = format!(
+        "{:04X}  {:02X} {:02X} {:02X} {:02X}  {:<24} ",
+        instruction.addr,
+        bytes[0],
+        bytes[1],
+        bytes[2],
+        bytes[3],
+        instruction.to_string()
+    );
+    for (index, register) in machine.registers().iter().enumerate() {
+        line += &format!("R{:X}:{:04X} ", index, *register as u16);
+    }
+    let flags = machine.flags();
+    line += &format!(
+        "C:{} Z:{} O:{} N:{} SP:{:04X}",
+        flags.C as u8,
+        flags.Z as u8,
+        flags.O as u8,
+        flags.N as u8,
+        machine.sp()
+    );
+    return line;
+}
+
+/// Formats the trace line for the instruction at PC in mash16's layout.
+pub fn mash16_line(machine: &Machine) -> String {
+    let pc = machine.pc();
+    let mut line = format!("{:04x}:", pc);
+    for offset in 0..4 {
+        line += &format!(
+            " {:02x}",
+            machine.mem()[usize::from(pc.wrapping_add(offset))]
+        );
+    }
+    line += " |";
+    for (index, register) in machine.registers().iter().enumerate() {
+        line += &format!(" r{:x}={:04x}", index, *register as u16);
+    }
+    line += &format!(
+        " | sp={:04x} f={:02x}",
+        machine.sp(),
+        machine.flags().to_byte()
+    );
+    return line;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.0.borrow_mut().write(buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn traces_between_conditions() {
+        // ADDI r0, 1; VBLNK; JMP 0x0000
+        let mut machine = Machine::with_program(&[
+            0x40, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+        ]);
+        let out = Shared::default();
+        machine.set_tracer(Some(Tracer::new(
+            Box::new(out.clone()),
+            Format::Default,
+            Some(Condition::Frame(1)),
+            Some(Condition::Pc(0x0004)),
+        )));
+        for _ in 0..3 {
+            machine.run_frame().unwrap();
+        }
+        machine.set_tracer(None).unwrap().finish().unwrap();
+
+        let trace = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "0008  10 00 00 00  jmp 0x0000               R0:0001 R1:0000 R2:0000 R3:0000 \
+             R4:0000 R5:0000 R6:0000 R7:0000 R8:0000 R9:0000 RA:0000 RB:0000 RC:0000 \
+             RD:0000 RE:0000 RF:0000 C:0 Z:0 O:0 N:0 SP:FDF0"
+        );
+        assert!(lines[1].starts_with("0000  40 00 01 00  addi r0, 0x0001          R0:0001"));
+    }
+
+    #[test]
+    fn traces_in_mash16_format() {
+        // LDI r0, 0x1234; SUBI r0, 0x1234
+        let mut machine = Machine::with_program(&[0x20, 0x00, 0x34, 0x12, 0x50, 0x00, 0x34, 0x12]);
+        let out = Shared::default();
+        machine.set_tracer(Some(Tracer::new(
+            Box::new(out.clone()),
+            Format::Mash16,
+            None,
+            None,
+        )));
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        machine.set_tracer(None).unwrap().finish().unwrap();
+
+        let trace = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines[2],
+            "0008: 00 00 00 00 | r0=0000 r1=0000 r2=0000 r3=0000 r4=0000 r5=0000 r6=0000 \
+             r7=0000 r8=0000 r9=0000 ra=0000 rb=0000 rc=0000 rd=0000 re=0000 rf=0000 | \
+             sp=fdf0 f=04"
+        );
+        assert!(lines[1].starts_with("0004: 50 00 34 12 | r0=1234 r1=0000"));
+        assert_eq!(Format::parse("MASH16"), Ok(Format::Mash16));
+        assert!(Format::parse("bochs").is_err());
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(Condition::parse("pc:0x0244"), Ok(Condition::Pc(0x0244)));
+        assert_eq!(Condition::parse("pc:1F0"), Ok(Condition::Pc(0x01F0)));
+        assert_eq!(Condition::parse("frame:120"), Ok(Condition::Frame(120)));
+        assert!(Condition::parse("line:3").is_err());
+        assert!(Condition::parse("pc:zz").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u32 = 2;
+/// The most samples the 32-bit sizes in the header can describe.
+const MAX_SAMPLES: u32 = (u32::MAX - HEADER_SIZE) / BYTES_PER_SAMPLE;
+
+/// Writes mono 16-bit PCM to a WAV file. The sizes in the header are brought
+/// up to date after every second of audio, so the file plays even if the
+/// emulator never gets to finish it.
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    samples: u32,
+    /// Samples written since the header was last brought up to date.
+    unsynced: u32,
+}
+
+fn header(sample_rate: u32, samples: u32) -> Vec<u8> {
+    let data_size = samples * BYTES_PER_SAMPLE;
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend(b"RIFF");
+    header.extend((HEADER_SIZE - 8 + data_size).to_le_bytes());
+    header.extend(b"WAVEfmt ");
+    header.extend(16u32.to_le_bytes());
+    // PCM, one channel
+    header.extend(1u16.to_le_bytes());
+    header.extend(1u16.to_le_bytes());
+    header.extend(sample_rate.to_le_bytes());
+    header.extend((sample_rate * BYTES_PER_SAMPLE).to_le_bytes());
+    header.extend((BYTES_PER_SAMPLE as u16).to_le_bytes());
+    header.extend(16u16.to_le_bytes());
+    header.extend(b"data");
+    header.extend(data_size.to_le_bytes());
+    return header;
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<WavWriter, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(&header(sample_rate, 0))
+            .and_then(|_| writer.flush())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        return Ok(WavWriter {
+            writer,
+            sample_rate,
+            samples: 0,
+            unsynced: 0,
+        });
+    }
+
+    /// Appends samples in the range -1.0 to 1.0, clipping any outside it.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        if samples.len() as u64 > (MAX_SAMPLES - self.samples) as u64 {
+            return Err(io::Error::other(
+                "the WAV file is as long as the format allows",
+            ));
+        }
+        let mut data = Vec::with_capacity(samples.len() * BYTES_PER_SAMPLE as usize);
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            data.extend(sample.to_le_bytes());
+        }
+        self.writer.write_all(&data)?;
+        self.samples += samples.len() as u32;
+        self.unsynced += samples.len() as u32;
+        if self.unsynced >= self.sample_rate {
+            self.sync()?;
+        }
+        return Ok(());
+    }
+
+    /// Brings the header up to date and flushes everything written to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer
+            .write_all(&header(self.sample_rate, self.samples))?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        self.unsynced = 0;
+        return Ok(());
+    }
+}
+
+/// Moves a writer to a thread of its own and returns a channel to send it
+/// samples on, so a sender such as an audio callback never waits on the disk.
+/// The thread finishes the file once every sender has been dropped.
+pub fn spawn_writer(mut writer: WavWriter) -> (Sender<Vec<f32>>, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::channel::<Vec<f32>>();
+    let handle = thread::spawn(move || {
+        for samples in receiver {
+            if let Err(e) = writer.write(&samples) {
+                println!("Stopped recording audio: {}", e);
+                break;
+            }
+        }
+    });
+    return (sender, handle);
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        return u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    }
+
+    #[test]
+    fn keeps_the_header_up_to_date() {
+        let path = std::env::temp_dir().join(format!("chip16-wav-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 4).unwrap();
+        wav.write(&[0.0, 1.0, -1.0]).unwrap();
+
+        // Less than a second in, the header still says it's empty
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44);
+        assert_eq!(u32_at(&bytes, 40), 0);
+
+        wav.write(&[2.0, 0.5]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 10);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 24), 4);
+        assert_eq!(u32_at(&bytes, 28), 8);
+        assert_eq!(u32_at(&bytes, 40), 10);
+        let samples: Vec<i16> = bytes[44..]
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        assert_eq!(samples, vec![0, 32767, -32767, 32767, 16383]);
+
+        wav.write(&[0.0]).unwrap();
+        drop(wav);
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u32_at(&bytes, 40), 12);
+        assert_eq!(bytes.len(), 44 + 12);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn writes_on_its_own_thread() {
+        let path =
+            std::env::temp_dir().join(format!("chip16-wav-thread-{}.wav", std::process::id()));
+        let (sender, handle) = spawn_writer(WavWriter::create(&path, 4).unwrap());
+        sender.send(vec![0.0, 1.0]).unwrap();
+        sender.send(vec![-1.0]).unwrap();
+        drop(sender);
+        handle.join().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u32_at(&bytes, 40), 6);
+        assert_eq!(bytes.len(), 44 + 6);
+        std::fs::remove_file(path).unwrap();
+    }
+}
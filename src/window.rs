@@ -0,0 +1,355 @@
+use crate::audio;
+use crate::input::Input;
+use crate::renderer::Renderer;
+use crate::speed::Speed;
+use crate::{
+    create_capture, finish_capture, load_symbols, parse_rom, print_warnings, run_frame, Args,
+    ContinuedFaults, FaultPolicy, MovieMode,
+};
+use chip16::bindings::Bindings;
+use chip16::debugger::Debugger;
+use chip16::fault::crash_report;
+use chip16::frame;
+use chip16::rewind::Rewind;
+use chip16::state::State;
+use chip16::wav::{self, WavWriter};
+use chip16::{Machine, GRID_X_SIZE, GRID_Y_SIZE};
+use sdl2::audio::AudioSpecDesired;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::EventPump;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FRAME_DURATION: Duration = Duration::new(0, 1_000_000_000u32 / 60);
+
+/// Save state slots live next to the ROM, e.g. `alien.state1`.
+fn state_slot_path(args: &Args, slot: u8) -> PathBuf {
+    let rom_path = args.rom_path.as_deref().unwrap_or_default();
+    return Path::new(rom_path).with_extension(format!("state{}", slot));
+}
+
+/// Screenshots are saved next to the ROM too, numbered from 1 without
+/// overwriting earlier ones, e.g. `alien-1.png`.
+fn screenshot_path(args: &Args) -> PathBuf {
+    let rom_path = Path::new(args.rom_path.as_deref().unwrap_or_default());
+    let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+    return (1..)
+        .map(|index| rom_path.with_file_name(format!("{}-{}.png", stem, index)))
+        .find(|path| !path.exists())
+        .unwrap_or_default();
+}
+
+/// F12 saves the current frame as a PNG.
+fn screenshot_hotkey(args: &Args, cpu: &Machine) -> String {
+    let path = screenshot_path(args);
+    return match frame::save(&path, &frame::to_rgb(cpu), GRID_X_SIZE, GRID_Y_SIZE) {
+        Ok(()) => format!("Saved screenshot {}", path.display()),
+        Err(e) => format!("Failed to save {}: {}", path.display(), e),
+    };
+}
+
+/// F1-F9 load the numbered save state slot and Shift+F1-F9 save to it.
+fn state_hotkey(args: &Args, cpu: &mut Machine, slot: u8, save: bool) -> String {
+    let path = state_slot_path(args, slot);
+    let result = if save {
+        cpu.state().save(&path).map(|_| "Saved")
+    } else {
+        State::load(&path).map(|state| {
+            cpu.restore(&state);
+            "Loaded"
+        })
+    };
+    return match result {
+        Ok(action) => format!("{} state {}", action, path.display()),
+        Err(e) => e,
+    };
+}
+
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    let slots = [
+        Keycode::F1,
+        Keycode::F2,
+        Keycode::F3,
+        Keycode::F4,
+        Keycode::F5,
+        Keycode::F6,
+        Keycode::F7,
+        Keycode::F8,
+        Keycode::F9,
+    ];
+    return slots
+        .iter()
+        .position(|slot| *slot == keycode)
+        .map(|index| index as u8 + 1);
+}
+
+pub fn run_window(args: &Args) -> Result<(), String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let audio_subsystem = sdl_context.audio()?;
+    let controller_subsystem = sdl_context.game_controller()?;
+
+    let bindings = match &args.bindings {
+        Some(path) => Bindings::load(Path::new(path))?,
+        None => Bindings::default(),
+    };
+    let mut input = Input::new(controller_subsystem, &bindings)?;
+
+    let desired_spec = AudioSpecDesired {
+        freq: Some(chip16::AUDIO_SAMPLE_RATE),
+        channels: Some(1),
+        // mono  -
+        samples: Some(1024),
+    };
+
+    // The callback sends what it plays to a thread that writes the file, at
+    // the rate the device was opened with
+    let mut audio_writer = None;
+    let mut record_error = None;
+    let audio_device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
+        let recorder = match &args.record_audio {
+            Some(path) => match WavWriter::create(Path::new(path), spec.freq as u32) {
+                Ok(writer) => {
+                    let (sender, handle) = wav::spawn_writer(writer);
+                    audio_writer = Some(handle);
+                    Some(sender)
+                }
+                Err(e) => {
+                    record_error = Some(e);
+                    None
+                }
+            },
+            None => None,
+        };
+        audio::default_wave(recorder)
+    })?;
+    if let Some(e) = record_error {
+        return Err(e);
+    }
+    // Recordings start with the ROM rather than its first sound
+    if audio_writer.is_some() {
+        audio_device.resume();
+    }
+
+    let audio_state = audio::AudioState::new(audio_device);
+
+    let mut event_pump = sdl_context.event_pump()?;
+
+    let rom_path = Path::new(args.rom_path.as_deref().unwrap_or_default());
+    let title = match rom_path.file_name() {
+        Some(name) => format!("Chip16 - {}", name.to_string_lossy()),
+        None => String::from("Chip16"),
+    };
+    let window = video_subsystem
+        .window(&title, GRID_X_SIZE * args.scale, GRID_Y_SIZE * args.scale)
+        .position_centered()
+        .resizable()
+        .opengl()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut renderer = Renderer::new(canvas, &texture_creator, args.scale_mode, args.filter)?;
+    if args.fullscreen {
+        renderer.set_fullscreen(true)?;
+    }
+
+    let (mut cpu, mut movie) = parse_rom(args, Box::new(audio_state))?;
+
+    let debugger = args.debug.then(Debugger::new);
+    run(
+        args,
+        &mut cpu,
+        &mut event_pump,
+        &mut input,
+        &mut renderer,
+        debugger,
+        &mut movie,
+    )?;
+    if let Some(movie) = &movie {
+        movie.finish(args)?;
+    }
+    if let Some(tracer) = cpu.set_tracer(None) {
+        tracer.finish()?;
+    }
+    // Closing the audio device drops the callback and its sender, so the
+    // writer thread finishes the file
+    drop(cpu);
+    if let Some(audio_writer) = audio_writer {
+        audio_writer
+            .join()
+            .map_err(|_| String::from("The audio writer thread panicked"))?;
+    }
+    Ok(())
+}
+
+/// Reads debugger commands from stdin on a separate thread so the window
+/// keeps rendering while we wait for input.
+fn spawn_command_reader() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    return receiver;
+}
+
+pub fn run(
+    args: &Args,
+    cpu: &mut Machine,
+    event_pump: &mut EventPump,
+    input: &mut Input,
+    renderer: &mut Renderer,
+    mut debugger: Option<Debugger>,
+    movie: &mut Option<MovieMode>,
+) -> Result<(), String> {
+    let symbols = load_symbols(args)?;
+    if let Some(debugger) = &mut debugger {
+        debugger.set_symbols(symbols.clone());
+    }
+    let mut commands = debugger.as_ref().map(|_| {
+        println!("Paused at {:#06X}, type 'help' for commands", cpu.pc());
+        spawn_command_reader()
+    });
+
+    let mut rewind = Rewind::new(args.rewind as usize * chip16::FPS as usize);
+    let mut rewinding = false;
+    let mut speed = Speed::new(args.fast_forward);
+    let mut halted = false;
+    let mut capture = create_capture(args)?;
+    let mut continued = ContinuedFaults::default();
+
+    let mut previous_frame_time = Instant::now();
+    let mut previous_draw_time = Instant::now();
+    'running: loop {
+        let mut ran_frame = false;
+        match (&mut debugger, &commands) {
+            _ if rewinding => {
+                if let Some(state) = rewind.pop() {
+                    cpu.restore(&state);
+                    if let Some(movie) = movie {
+                        movie.rewind();
+                    }
+                }
+            }
+            (Some(debugger), Some(commands)) => {
+                for line in commands.try_iter() {
+                    let output = debugger.execute(&line, cpu);
+                    if !output.is_empty() {
+                        println!("{}", output);
+                    }
+                }
+                if debugger.should_quit() {
+                    break 'running;
+                }
+                if let Some(reason) = debugger.run_frame(cpu) {
+                    println!("{}", reason);
+                }
+                ran_frame = debugger.finished_frame();
+            }
+            _ if halted || !speed.should_run() => {}
+            _ => {
+                if let Some(movie) = movie {
+                    movie.before_frame(cpu);
+                }
+                ran_frame = true;
+                if let Err(fault) = run_frame(cpu, args.on_fault, &mut continued) {
+                    println!("{}", crash_report(cpu, &fault, &symbols));
+                    if args.on_fault == FaultPolicy::Break {
+                        println!("Paused at {:#06X}, type 'help' for commands", cpu.pc());
+                        let mut fault_debugger = Debugger::new();
+                        fault_debugger.set_symbols(symbols.clone());
+                        debugger = Some(fault_debugger);
+                        commands = Some(spawn_command_reader());
+                    } else {
+                        renderer.set_title(&format!("Halted: {}", fault))?;
+                        halted = true;
+                    }
+                }
+            }
+        }
+        if ran_frame {
+            rewind.push(&cpu.state());
+            if let Some(capture) = &mut capture {
+                capture.add_frame(cpu)?;
+            }
+        }
+        print_warnings(cpu);
+
+        let frame_speed = speed.frame_speed();
+        cpu.set_sound_speed(speed.sound_speed());
+
+        // Uncapped fast-forward only draws as often as the display refreshes
+        if frame_speed.is_some() || previous_draw_time.elapsed() >= FRAME_DURATION {
+            renderer.draw(cpu)?;
+            previous_draw_time = Instant::now();
+        }
+
+        let frame_duration = match frame_speed {
+            Some(frame_speed) => FRAME_DURATION.div_f64(frame_speed),
+            None => Duration::ZERO,
+        };
+        let passed_duration = previous_frame_time.elapsed();
+        if passed_duration < frame_duration {
+            let sleep_duration = frame_duration - passed_duration;
+            thread::sleep(sleep_duration);
+        }
+
+        for event in event_pump.poll_iter() {
+            // Bindings can't use hotkeys, so every event goes to the pads
+            // first and none of their releases are missed
+            input.handle(&event);
+            match event {
+                Event::Quit { .. } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if state_slot(keycode).is_some() => {
+                    let slot = state_slot(keycode).unwrap_or_default();
+                    let save = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    if movie.is_some() && !save {
+                        println!("Can't load states while recording or playing a movie");
+                        continue;
+                    }
+                    println!("{}", state_hotkey(args, cpu, slot, save));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => println!("{}", screenshot_hotkey(args, cpu)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = !matches!(movie, Some(MovieMode::Play(..))),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = false,
+                event if speed.handle(&event) => {}
+                event if renderer.handle(&event)? => {}
+                _ => {}
+            }
+        }
+
+        input.latch(cpu);
+
+        previous_frame_time = match frame_speed {
+            Some(_) => previous_frame_time + frame_duration,
+            None => Instant::now(),
+        };
+    }
+    continued.print_repeats();
+    finish_capture(args, capture)?;
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Reassembles the sources in the bundled program pack and checks the output
+//! matches the shipped ROMs byte for byte, header included.
+
+#![allow(clippy::needless_return)]
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use chip16::asm;
+use chip16::rom::Rom;
+
+const ROM_PACK: &str = "Chip16 program pack 09.04.2018.zip";
+
+// Chip1613ST imports a file by an absolute Windows path, so it's left out.
+const SOURCES: [(&str, &str); 24] = [
+    ("AdsrDemo/AdsrDemo.asm", "Demos/AdsrDemo.c16"),
+    ("AdsrTest.asm", "Test roms/AdsrTest.c16"),
+    ("Anim/Anim.asm", "Demos/Anim.c16"),
+    ("ASCII.asm", "Demos/ASCII.c16"),
+    ("Ball.asm", "Demos/Ball.c16"),
+    ("BC_TestRom.asm", "Test roms/BC_TestRom.c16"),
+    ("CollisionTest.asm", "Test roms/CollisionTest.c16"),
+    ("GB16.asm", "Demos/GB16.c16"),
+    ("Herdle/Herdle.asm", "Games/Herdle.c16"),
+    ("Mandel/mandel.ASM", "Demos/Mandel.c16"),
+    ("Maze.asm", "Demos/Maze.c16"),
+    ("MusicMaker/MusicMaker.asm", "Games/MusicMaker.c16"),
+    ("Ninja/ninja.asm", "Games/Ninja.c16"),
+    ("PadTest.asm", "Test roms/PadTest.c16"),
+    ("PaletteFlip/PaletteFlip.asm", "Test roms/PaletteFlip.c16"),
+    ("PCBIOS/Chip16.asm", "Demos/PCBIOS.c16"),
+    ("Pong.asm", "Games/Pong.c16"),
+    ("Reflection/Reflection.asm", "Games/Reflection.c16"),
+    ("SFX.s", "Demos/SFX.c16"),
+    ("Sokoban_src/Sokoban.asm", "Games/Sokoban.c16"),
+    ("SoundTest.asm", "Test roms/SoundTest.c16"),
+    ("Starfield.asm", "Demos/Starfield.c16"),
+    ("Stopwatch/Stopwatch.asm", "Demos/Stopwatch.c16"),
+    ("triangle.ASM", "Demos/Triangle.c16"),
+];
+
+/// Extracts the pack's ROMs and Sources directories so includes and
+/// importbin can find their files. Ninja's sources expect its images next to
+/// ninja.asm rather than in bin/, so they're copied there.
+fn extract_pack() -> PathBuf {
+    let pack = Path::new(env!("CARGO_MANIFEST_DIR")).join(ROM_PACK);
+    let mut archive =
+        zip::ZipArchive::new(File::open(pack).expect("Failed to open ROM pack")).unwrap();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("program_pack");
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).unwrap();
+        let path = match entry.enclosed_name() {
+            Some(path) if !entry.is_dir() => dir.join(path),
+            _ => continue,
+        };
+        let mut bytes = vec![];
+        entry.read_to_end(&mut bytes).unwrap();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    let ninja = dir.join("Sources").join("Ninja");
+    for entry in std::fs::read_dir(ninja.join("bin")).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "bin") {
+            std::fs::copy(&path, ninja.join(path.file_name().unwrap())).unwrap();
+        }
+    }
+    return dir;
+}
+
+#[test]
+fn reassembles_program_pack() {
+    let dir = extract_pack();
+
+    for (source, rom) in SOURCES {
+        let assembly = asm::assemble_file(&dir.join("Sources").join(source))
+            .unwrap_or_else(|e| panic!("{}", e));
+        let expected = std::fs::read(dir.join("ROMs").join(rom)).unwrap();
+        let spec = Rom::from_bytes(expected.clone(), true)
+            .unwrap()
+            .spec_version()
+            .unwrap();
+
+        let actual = assembly.to_rom(spec).to_bytes();
+        assert!(
+            actual == expected,
+            "{} doesn't reassemble to {}",
+            source,
+            rom
+        );
+    }
+}
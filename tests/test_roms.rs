@@ -0,0 +1,214 @@
+//! Golden-image regression tests over the test ROMs in the bundled program
+//! pack. Each ROM runs headless for a fixed number of frames with scripted
+//! input and the final frame is compared against `tests/golden/<rom>.png`.
+//!
+//! Run with `UPDATE_GOLDEN=1` to regenerate the golden images.
+
+#![allow(clippy::needless_return)]
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use chip16::cpu::Controller;
+use chip16::movie::Movie;
+use chip16::rom::{Rom, RomError};
+use chip16::sound::NullSound;
+use chip16::state::State;
+use chip16::timing::Clock;
+use chip16::{frame, Machine, GRID_X_SIZE, GRID_Y_SIZE};
+
+const ROM_PACK: &str = "Chip16 program pack 09.04.2018.zip";
+const TEST_ROM_DIR: &str = "ROMs/Test roms";
+
+/// Controller state to apply at the start of a frame: (frame, pad, state).
+type InputEvent = (u32, usize, Controller);
+
+fn read_test_rom(name: &str) -> Vec<u8> {
+    let pack = Path::new(env!("CARGO_MANIFEST_DIR")).join(ROM_PACK);
+    let mut archive =
+        zip::ZipArchive::new(File::open(pack).expect("Failed to open ROM pack")).unwrap();
+    let mut entry = archive
+        .by_name(&format!("{}/{}.c16", TEST_ROM_DIR, name))
+        .expect("ROM missing from pack");
+    let mut rom = vec![];
+    entry.read_to_end(&mut rom).unwrap();
+    return rom;
+}
+
+fn load_machine(rom: Vec<u8>) -> Machine {
+    // Chip1613ST ships with a wrong CRC, so that's checked separately in rom_crcs
+    let rom = Rom::from_bytes(rom, false).expect("Failed to load ROM");
+    let mut machine = Machine::from_rom(&rom, Box::new(NullSound));
+    machine.seed_rng(0);
+    return machine;
+}
+
+// FNV-1a, so failures can report a short fingerprint of each frame.
+fn hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    return Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.png", name));
+}
+
+fn read_golden(path: &Path) -> Vec<u8> {
+    let decoder = png::Decoder::new(File::open(path).expect("Missing golden image"));
+    let mut reader = decoder.read_info().unwrap();
+    let mut rgb = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut rgb).unwrap();
+    rgb.truncate(info.buffer_size());
+    return rgb;
+}
+
+fn run_golden(name: &str, frames: u32, input: &[InputEvent]) {
+    let mut machine = load_machine(read_test_rom(name));
+
+    for frame in 0..frames {
+        for (_, pad, state) in input.iter().filter(|event| event.0 == frame) {
+            machine.set_controller(*pad, *state);
+        }
+        machine.run_frame().unwrap();
+    }
+
+    let actual = frame::to_rgb(&machine);
+    let golden = golden_path(name);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(golden.parent().unwrap()).unwrap();
+        frame::save(&golden, &actual, GRID_X_SIZE, GRID_Y_SIZE).unwrap();
+        return;
+    }
+
+    let expected = read_golden(&golden);
+    if hash(&actual) != hash(&expected) {
+        let failed = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.png", name));
+        frame::save(&failed, &actual, GRID_X_SIZE, GRID_Y_SIZE).unwrap();
+        panic!(
+            "{} frame hash {:016x} does not match golden {:016x}, actual frame saved to {}",
+            name,
+            hash(&actual),
+            hash(&expected),
+            failed.display()
+        );
+    }
+}
+
+#[test]
+fn rom_crcs() {
+    for name in [
+        "BC_TestRom",
+        "flip_test",
+        "CollisionTest",
+        "PaleteTest",
+        "PaletteFlip",
+        "PadTest",
+    ] {
+        let rom = Rom::from_bytes(read_test_rom(name), true).unwrap();
+        assert_eq!(rom.spec_version(), Some((1, 1)));
+    }
+
+    match Rom::from_bytes(read_test_rom("Chip1613ST"), true) {
+        Err(RomError::CrcMismatch { .. }) => {}
+        _ => panic!("Chip1613ST should fail its CRC check"),
+    }
+}
+
+#[test]
+fn bc_test_rom() {
+    run_golden("BC_TestRom", 300, &[]);
+}
+
+#[test]
+fn chip16_13_spec_test() {
+    run_golden("Chip1613ST", 300, &[]);
+}
+
+#[test]
+fn flip_test() {
+    run_golden("flip_test", 120, &[]);
+}
+
+#[test]
+fn collision_test() {
+    run_golden("CollisionTest", 120, &[]);
+}
+
+#[test]
+fn palette_test() {
+    run_golden("PaleteTest", 120, &[]);
+}
+
+#[test]
+fn palette_flip() {
+    run_golden("PaletteFlip", 120, &[]);
+}
+
+#[test]
+fn pad_test() {
+    // Hold UP and A on the first pad and RIGHT and START on the second.
+    run_golden("PadTest", 120, &[(60, 0, 0b01000001), (60, 1, 0b00101000)]);
+}
+
+#[test]
+fn save_state_resumes_identically() {
+    // CollisionTest draws with RND, so this also covers the RNG state
+    let mut machine = load_machine(read_test_rom("CollisionTest"));
+    for _ in 0..60 {
+        machine.run_frame().unwrap();
+    }
+    let saved = State::from_bytes(&machine.state().to_bytes()).unwrap();
+    for _ in 0..60 {
+        machine.run_frame().unwrap();
+    }
+
+    let mut restored = load_machine(read_test_rom("CollisionTest"));
+    restored.seed_rng(1);
+    restored.restore(&saved);
+    for _ in 0..60 {
+        restored.run_frame().unwrap();
+    }
+    assert_eq!(frame::to_rgb(&restored), frame::to_rgb(&machine));
+    assert!(restored.state() == machine.state());
+}
+
+#[test]
+fn movie_replays_bit_exactly() {
+    let rom = Rom::from_bytes(read_test_rom("CollisionTest"), true).unwrap();
+    let input: [InputEvent; 3] = [(10, 0, 0b00001001), (40, 1, 0b10000000), (70, 0, 0)];
+
+    let mut recording = Machine::from_rom(&rom, Box::new(NullSound));
+    recording.seed_rng(1234);
+    recording.set_clock(Clock::Hz(2_000_000));
+    let mut movie = Movie::new(1234, &rom, &recording);
+    for frame in 0..120 {
+        for (_, pad, state) in input.iter().filter(|event| event.0 == frame) {
+            recording.set_controller(*pad, *state);
+        }
+        movie.record(&recording);
+        recording.run_frame().unwrap();
+    }
+
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    assert!(movie.matches(&rom));
+    assert_eq!(movie.clock, Clock::Hz(2_000_000));
+    assert_eq!(movie.spec, rom.spec_version().unwrap());
+    let mut playback = Machine::from_rom(&rom, Box::new(NullSound));
+    movie.prepare(&mut playback);
+    let mut frame = 0;
+    while movie.play(frame, &mut playback) {
+        playback.run_frame().unwrap();
+        frame += 1;
+    }
+    assert_eq!(frame, 120);
+    assert!(playback.state() == recording.state());
+}